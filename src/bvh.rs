@@ -0,0 +1,259 @@
+use cgmath::{ElementWise, Vector3};
+
+use crate::cpu_tracer::MAX_FLOAT;
+use crate::cs;
+use crate::object_traits::Uniform;
+use crate::scene::Scene;
+
+// The shader walks the tree with a fixed size stack, which never holds more than one entry per
+// level plus the one being visited. Keep BVH_STACK_SIZE in the shader at MAX_DEPTH + 1
+const MAX_DEPTH: usize = 63;
+
+// Leaves with up to this many primitives are kept whole when no split looks cheaper
+const MAX_LEAF_SIZE: usize = 4;
+
+// How much primitive bounds get padded by, relative to how far they are from the origin. Rays that
+// only graze a primitive, or run exactly along a face of its box, would otherwise miss the box
+// from rounding while still hitting the primitive
+const BOUNDS_PADDING: f32 = 0.00001;
+
+// Centroids are sorted into this many buckets along the split axis, and only the boundaries
+// between buckets are considered as split positions
+const BINS: usize = 12;
+
+/// Something the BVH can point at. Triangles are numbered across all meshes, in the order
+/// `Scene::triangles` returns them
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Primitive {
+    Sphere(usize),
+    Triangle(usize),
+    Shape(usize),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub(crate) min: Vector3<f32>,
+    pub(crate) max: Vector3<f32>,
+}
+
+/// One node of the flattened tree. An interior node's first child comes right after it and
+/// `offset` points at the second. A leaf's primitives are `primitives[offset..offset + count]`
+#[derive(Debug, Copy, Clone)]
+pub struct Node {
+    pub(crate) bounds: Aabb,
+    pub(crate) offset: u32,
+    pub(crate) count: u32,
+}
+
+/// Bounding volume hierarchy over every sphere, triangle and shape in a scene, built with the surface
+/// area heuristic and laid out depth first so the shader can walk it without pointers
+pub struct Bvh {
+    pub(crate) nodes: Vec<Node>,
+    pub(crate) primitives: Vec<Primitive>,
+}
+
+// A primitive waiting to be put into a leaf
+struct BuildItem {
+    primitive: Primitive,
+    bounds: Aabb,
+    centroid: Vector3<f32>,
+}
+
+impl Bvh {
+    pub fn build(scene: &Scene) -> Self {
+        let spheres = scene.spheres.iter().enumerate().map(|(i, sphere)| {
+            let radius = Vector3::new(1.0, 1.0, 1.0) * sphere.radius;
+            let center = sphere.center.truncate();
+            (Primitive::Sphere(i), Aabb { min: center - radius, max: center + radius })
+        });
+        let triangles = scene.triangles().enumerate().map(|(i, (mesh, [a, b, c]))| {
+            let bounds = Aabb::empty()
+                .grow(mesh.positions[a as usize])
+                .grow(mesh.positions[b as usize])
+                .grow(mesh.positions[c as usize]);
+            (Primitive::Triangle(i), bounds)
+        });
+        let shapes = scene.shapes.iter().enumerate().map(|(i, shape)| (Primitive::Shape(i), shape.bounds()));
+        let mut items: Vec<_> = spheres.chain(triangles).chain(shapes)
+            .map(|(primitive, bounds)| BuildItem { primitive, bounds: bounds.padded(), centroid: bounds.centroid() })
+            .collect();
+
+        let mut bvh = Bvh { nodes: Vec::new(), primitives: Vec::with_capacity(items.len()) };
+        if items.is_empty() {
+            // Buffers can't be empty, so there's still a root. It looks like an interior node and
+            // every ray enters its inside out bounds, so tracers check for no primitives instead of
+            // walking it
+            bvh.nodes.push(Node { bounds: Aabb::empty(), offset: 0, count: 0 });
+        } else {
+            bvh.build_node(&mut items, 0);
+        }
+        bvh
+    }
+
+    // Appends the subtree over `items` and returns the index of its root
+    fn build_node(&mut self, items: &mut [BuildItem], depth: usize) -> usize {
+        let index = self.nodes.len();
+        let bounds = items.iter().fold(Aabb::empty(), |bounds, item| bounds.union(&item.bounds));
+        self.nodes.push(Node { bounds, offset: 0, count: 0 });
+
+        let split = if depth < MAX_DEPTH { find_split(items, &bounds) } else { None };
+        match split {
+            Some(mid) => {
+                let (left, right) = items.split_at_mut(mid);
+                self.build_node(left, depth + 1);
+                let right = self.build_node(right, depth + 1);
+                self.nodes[index].offset = right as u32;
+            }
+            None => {
+                self.nodes[index].offset = self.primitives.len() as u32;
+                self.nodes[index].count = items.len() as u32;
+                self.primitives.extend(items.iter().map(|item| item.primitive));
+            }
+        }
+        index
+    }
+}
+
+// Picks the cheapest bucket boundary along the axis the centroids are most spread out on and
+// partitions `items` around it, returning how many ended up on the left. None means the items
+// are better off staying together in a leaf
+fn find_split(items: &mut [BuildItem], bounds: &Aabb) -> Option<usize> {
+    if items.len() <= 1 {
+        return None;
+    }
+
+    let centroid_bounds = items.iter().fold(Aabb::empty(), |bounds, item| bounds.grow(item.centroid));
+    let extent = centroid_bounds.max - centroid_bounds.min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+    // Every centroid is in the same spot, there's no way to tell the items apart
+    if extent[axis] <= 0.0 {
+        return None;
+    }
+
+    let bin_of = |item: &BuildItem| {
+        let offset = (item.centroid[axis] - centroid_bounds.min[axis]) / extent[axis];
+        ((offset * BINS as f32) as usize).min(BINS - 1)
+    };
+
+    let mut bin_bounds = [Aabb::empty(); BINS];
+    let mut bin_counts = [0usize; BINS];
+    for item in items.iter() {
+        let bin = bin_of(item);
+        bin_bounds[bin] = bin_bounds[bin].union(&item.bounds);
+        bin_counts[bin] += 1;
+    }
+
+    // Cost of splitting after each bin, in units of one primitive intersection, with a node visit
+    // costing as much as an intersection
+    let mut best: Option<(usize, f32)> = None;
+    for split in 1..BINS {
+        let (left, right) = bin_counts.split_at(split);
+        let (left_count, right_count) = (left.iter().sum::<usize>(), right.iter().sum::<usize>());
+        if left_count == 0 || right_count == 0 {
+            continue;
+        }
+        let left_bounds = bin_bounds[..split].iter().fold(Aabb::empty(), |a, b| a.union(b));
+        let right_bounds = bin_bounds[split..].iter().fold(Aabb::empty(), |a, b| a.union(b));
+        let cost = 1.0 + (left_bounds.surface_area() * left_count as f32
+            + right_bounds.surface_area() * right_count as f32) / bounds.surface_area();
+        if best.map_or(true, |(_, best_cost)| cost < best_cost) {
+            best = Some((split, cost));
+        }
+    }
+
+    let (split, cost) = best?;
+    if cost >= items.len() as f32 && items.len() <= MAX_LEAF_SIZE {
+        return None;
+    }
+
+    // Partition in place, everything left of the split bin first
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if bin_of(&items[i]) < split {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    Some(mid)
+}
+
+impl Aabb {
+    /// A box containing nothing, which growing by anything replaces. Uses the shader's MAX_FLOAT
+    /// rather than infinity so it survives the trip to the GPU intact
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vector3::new(MAX_FLOAT, MAX_FLOAT, MAX_FLOAT),
+            max: Vector3::new(-MAX_FLOAT, -MAX_FLOAT, -MAX_FLOAT),
+        }
+    }
+
+    pub fn grow(&self, point: Vector3<f32>) -> Self {
+        self.union(&Aabb { min: point, max: point })
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Aabb {
+            min: Vector3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Vector3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    fn padded(&self) -> Self {
+        let padding = |a: f32, b: f32| BOUNDS_PADDING * (1.0 + a.abs().max(b.abs()));
+        let padding = Vector3::new(
+            padding(self.min.x, self.max.x),
+            padding(self.min.y, self.max.y),
+            padding(self.min.z, self.max.z),
+        );
+        Aabb { min: self.min - padding, max: self.max + padding }
+    }
+
+    pub fn centroid(&self) -> Vector3<f32> {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let extent = self.max - self.min;
+        if extent.x < 0.0 {
+            return 0.0;
+        }
+        2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+    }
+}
+
+/// Slab test. Returns where the ray enters the box within `t_min..=t_max`, or `MAX_FLOAT` if it
+/// doesn't. `inv_d` is one over each component of the ray direction
+pub fn intersect_ray_box(p: Vector3<f32>, inv_d: Vector3<f32>, bounds: &Aabb, t_min: f32, t_max: f32) -> f32 {
+    let t0 = (bounds.min - p).mul_element_wise(inv_d);
+    let t1 = (bounds.max - p).mul_element_wise(inv_d);
+    let t_enter = t0.x.min(t1.x).max(t0.y.min(t1.y)).max(t0.z.min(t1.z)).max(t_min);
+    let t_exit = t0.x.max(t1.x).min(t0.y.max(t1.y)).min(t0.z.max(t1.z)).min(t_max);
+    if t_enter <= t_exit { t_enter } else { MAX_FLOAT }
+}
+
+impl Uniform for Node {
+    type Uniform = cs::ty::BvhNode;
+
+    fn to_uniform(&self) -> Self::Uniform {
+        cs::ty::BvhNode {
+            boundsMin: self.bounds.min.into(),
+            offset: self.offset,
+            boundsMax: self.bounds.max.into(),
+            count: self.count,
+        }
+    }
+}
+
+impl Uniform for Primitive {
+    type Uniform = cs::ty::BvhPrimitive;
+
+    // Kinds match the HIT_ constants in the shader
+    fn to_uniform(&self) -> Self::Uniform {
+        let (kind, index) = match *self {
+            Primitive::Sphere(index) => (0, index),
+            Primitive::Triangle(index) => (1, index),
+            Primitive::Shape(index) => (3, index),
+        };
+        cs::ty::BvhPrimitive { kind, index: index as i32 }
+    }
+}
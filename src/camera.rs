@@ -1,15 +1,78 @@
-use cgmath::{Vector3, Rad, Matrix4, InnerSpace, Point3, EuclideanSpace};
+use cgmath::{Vector3, Rad, Deg, Matrix4, InnerSpace, Point3, EuclideanSpace};
+use serde::Deserialize;
 
 use crate::cs;
 use crate::object_traits::Uniform;
 
-#[derive(Debug)]
+// How far the mouse wheel can zoom in and out. Perspective views stretch out to nothing as they
+// near 180 degrees, so they stop short of it
+const MIN_FOV: Deg<f32> = Deg(1.0);
+const MAX_FOV: Deg<f32> = Deg(170.0);
+
+/// Widest a fisheye view goes, all the way around to straight behind at the edges
+pub(crate) const MAX_FISHEYE_FOV: Deg<f32> = Deg(360.0);
+
+// Smallest an orthographic view can zoom in to
+const MIN_ORTHO_HEIGHT: f32 = 0.01;
+
+// Each step of the mouse wheel narrows or widens the view by this factor
+const ZOOM_STEP: f32 = 0.9;
+
+/// How the camera lays the scene out over the image. Values match the PROJECTION_ constants in the
+/// shader
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum Projection {
+    /// Pinhole camera, straight lines stay straight
+    Perspective = 0,
+    /// Parallel rays, so things keep their size however far away they are
+    Orthographic = 1,
+    /// Angle from the middle of the image grows evenly with distance from it, out to half the
+    /// field of view at the top and bottom edges
+    Fisheye = 2,
+    /// Every direction at once, longitude across and latitude down, for 2:1 images
+    Equirectangular = 3,
+}
+
+impl Projection {
+    /// The one after this, for cycling through them all
+    pub fn next(self) -> Self {
+        match self {
+            Projection::Perspective => Projection::Orthographic,
+            Projection::Orthographic => Projection::Fisheye,
+            Projection::Fisheye => Projection::Equirectangular,
+            Projection::Equirectangular => Projection::Perspective,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Camera {
     pub(crate) position: Vector3<f32>,
     pub(crate) yaw: Rad<f32>,
     pub(crate) pitch: Rad<f32>,
     pub(crate) speed: f32,
     pub(crate) sensitivity: f32,
+    pub(crate) projection: Projection,
+    /// Vertical field of view of perspective and fisheye projections. Perspective ones stay under
+    /// 180 degrees, fisheye ones can go up to `MAX_FISHEYE_FOV`
+    pub(crate) fov: Rad<f32>,
+    /// Height of an orthographic view in world units
+    pub(crate) ortho_height: f32,
+    /// Width of the view over its height, 0 to match the image it's rendered into. Equirectangular
+    /// views always cover every direction whatever the image's shape
+    pub(crate) aspect: f32,
+    /// How far in front of the camera things have to be to show up. Perspective and orthographic
+    /// views measure it as depth, the others along each ray
+    pub(crate) near: f32,
+    /// Radius of the lens. Anything off the focus distance gets blurrier the bigger it is, 0 keeps
+    /// everything sharp
+    pub(crate) aperture: f32,
+    /// Depth in front of the camera that's in focus
+    pub(crate) focus_distance: f32,
+    /// How far apart the eyes of a stereo image are
+    pub(crate) eye_separation: f32,
+    /// Depth at which the two eyes' views line up, so things there look level with the screen
+    pub(crate) convergence: f32,
 }
 
 impl Camera {
@@ -20,10 +83,36 @@ impl Camera {
             pitch: cgmath::Deg(0.0).into(),
             speed: 1.5,
             sensitivity: 0.5,
+            projection: Projection::Perspective,
+            // A viewport one unit high, one unit away
+            fov: Rad(2.0 * 0.5f32.atan()),
+            ortho_height: 4.0,
+            aspect: 0.0,
+            near: 1.0,
+            aperture: 0.0,
+            focus_distance: 4.0,
+            // About a person's, taking units as meters
+            eye_separation: 0.064,
+            convergence: 4.0,
         }
     }
 
-    fn calc_rotation_matrix(&self) -> Matrix4<f32> {
+    /// Narrows the view by `steps` steps of the mouse wheel, or widens it for negative ones.
+    /// Equirectangular views already see everything, so they stay as they are
+    pub fn zoom(&mut self, steps: f32) {
+        let factor = ZOOM_STEP.powf(steps);
+        match self.projection {
+            Projection::Perspective | Projection::Fisheye => {
+                let max = if self.projection == Projection::Fisheye { MAX_FISHEYE_FOV } else { MAX_FOV };
+                let fov = self.fov.0 * factor;
+                self.fov = Rad(fov.clamp(Rad::from(MIN_FOV).0, Rad::from(max).0));
+            }
+            Projection::Orthographic => self.ortho_height = (self.ortho_height * factor).max(MIN_ORTHO_HEIGHT),
+            Projection::Equirectangular => {}
+        }
+    }
+
+    pub(crate) fn calc_rotation_matrix(&self) -> Matrix4<f32> {
         Matrix4::look_to_rh(
             Point3::from_vec(self.position),
             Vector3::new(
@@ -42,8 +131,34 @@ impl Uniform for Camera {
     fn to_uniform(&self) -> Self::Uniform {
         cs::ty::Camera {
             position: self.position.into(),
-            padding: 0.0,
-            rotation: self.calc_rotation_matrix().into()
+            near: self.near,
+            rotation: self.calc_rotation_matrix().into(),
+            fov: self.fov.0,
+            aspect: self.aspect,
+            aperture: self.aperture,
+            focusDistance: self.focus_distance,
+            projection: self.projection as i32,
+            orthoHeight: self.ortho_height,
+            eyeSeparation: self.eye_separation,
+            convergence: self.convergence,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zoom_limits_depend_on_the_projection() {
+        let mut camera = Camera::from_origin();
+        camera.zoom(-100.0);
+        assert!((Deg::from(camera.fov).0 - MAX_FOV.0).abs() < 1e-3);
+
+        camera.projection = Projection::Fisheye;
+        camera.zoom(-100.0);
+        assert!((Deg::from(camera.fov).0 - MAX_FISHEYE_FOV.0).abs() < 1e-3);
+        camera.zoom(100.0);
+        assert!((Deg::from(camera.fov).0 - MIN_FOV.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,176 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: ray_tracing_vulkano [OPTIONS]
+
+Options:
+    --scene <path>    Scene file to render (defaults to the built in demo scene)
+    --headless        Render a single frame to a file instead of opening a window
+    --output <path>   Where --headless writes the frame, as .png or .ppm (defaults to frame.png)
+    --cpu             Trace the --headless frame on the CPU instead of through Vulkan
+    --resolution <WxH>
+                      Size of the window or the --headless frame, overriding the scene file
+    --frames <n>      Frames to average for a path traced or accumulating --headless render
+                      (defaults to 64)
+    --samples <n>     Rays per pixel every frame, overriding the scene file
+    -h, --help        Print this message";
+
+/// Command line arguments
+#[derive(Debug)]
+pub struct Args {
+    pub scene: Option<PathBuf>,
+    pub headless: bool,
+    pub output: PathBuf,
+    pub cpu: bool,
+    pub resolution: Option<(u32, u32)>,
+    pub frames: u32,
+    pub samples: Option<u32>,
+    pub help: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            scene: None,
+            headless: false,
+            output: PathBuf::from("frame.png"),
+            cpu: false,
+            resolution: None,
+            frames: 64,
+            samples: None,
+            help: false,
+        }
+    }
+}
+
+impl Args {
+    pub fn parse() -> Result<Self, String> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    fn parse_from(mut raw: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut args = Args::default();
+        let mut output_given = false;
+        let mut frames_given = false;
+        while let Some(arg) = raw.next() {
+            match arg.as_str() {
+                "--scene" => args.scene = Some(value(&arg, raw.next())?.into()),
+                "--headless" => args.headless = true,
+                "--output" => {
+                    args.output = value(&arg, raw.next())?.into();
+                    output_given = true;
+                }
+                "--cpu" => args.cpu = true,
+                "--resolution" => args.resolution = Some(parse_resolution(&value(&arg, raw.next())?)?),
+                "--frames" => {
+                    args.frames = parse_frames(&value(&arg, raw.next())?)?;
+                    frames_given = true;
+                }
+                "--samples" => args.samples = Some(parse_samples(&value(&arg, raw.next())?)?),
+                "-h" | "--help" => args.help = true,
+                _ => return Err(format!("unknown argument '{}'", arg)),
+            }
+        }
+        if output_given && !args.headless {
+            return Err("--output only applies together with --headless".to_string());
+        }
+        if args.cpu && !args.headless {
+            return Err("--cpu only applies together with --headless".to_string());
+        }
+        if frames_given && !args.headless {
+            return Err("--frames only applies together with --headless".to_string());
+        }
+        Ok(args)
+    }
+}
+
+fn value(flag: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{} expects a value", flag))
+}
+
+// Parses sizes like 1280x720
+fn parse_resolution(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("invalid resolution '{}', expected something like 1280x720", value);
+    let mut parts = value.splitn(2, 'x');
+    let width: u32 = parts.next().unwrap().parse().map_err(|_| invalid())?;
+    let height: u32 = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+    if width == 0 || height == 0 {
+        return Err(invalid());
+    }
+    Ok((width, height))
+}
+
+fn parse_frames(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(frames) if frames > 0 => Ok(frames),
+        _ => Err(format!("invalid frame count '{}', expected a positive number", value)),
+    }
+}
+
+fn parse_samples(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(samples) if samples > 0 => Ok(samples),
+        _ => Err(format!("invalid sample count '{}', expected a positive number", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse_from(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn resolution() {
+        assert_eq!(parse(&["--resolution", "1280x720"]).unwrap().resolution, Some((1280, 720)));
+        for bad in &["1280", "1280x", "x720", "0x720", "1280x0", "1280x720x1", "-1x720", "widexhigh", "1280X720"] {
+            let error = parse(&["--resolution", bad]).unwrap_err();
+            assert!(error.contains("invalid resolution"), "{}: {}", bad, error);
+        }
+        assert_eq!(parse(&["--resolution"]).unwrap_err(), "--resolution expects a value");
+    }
+
+    #[test]
+    fn headless_defaults() {
+        let args = parse(&["--headless"]).unwrap();
+        assert!(args.headless);
+        assert_eq!(args.output, PathBuf::from("frame.png"));
+        assert!(!args.cpu);
+        assert_eq!(args.frames, 64);
+        assert_eq!(args.samples, None);
+        assert_eq!(args.resolution, None);
+        assert_eq!(args.scene, None);
+    }
+
+    #[test]
+    fn headless_options() {
+        let args = parse(&[
+            "--headless", "--cpu", "--frames", "8", "--samples", "4", "--output", "out.ppm", "--scene", "a.ron",
+        ]).unwrap();
+        assert!(args.cpu);
+        assert_eq!(args.frames, 8);
+        assert_eq!(args.samples, Some(4));
+        assert_eq!(args.output, PathBuf::from("out.ppm"));
+        assert_eq!(args.scene, Some(PathBuf::from("a.ron")));
+
+        // Samples apply to the window too, the rest only to headless renders
+        assert_eq!(parse(&["--samples", "2"]).unwrap().samples, Some(2));
+        assert!(parse(&["--cpu"]).is_err());
+        assert!(parse(&["--frames", "8"]).is_err());
+        assert!(parse(&["--output", "out.png"]).is_err());
+
+        for bad in &["0", "-1", "many"] {
+            assert!(parse(&["--headless", "--frames", bad]).unwrap_err().contains("invalid frame count"));
+            assert!(parse(&["--samples", bad]).unwrap_err().contains("invalid sample count"));
+        }
+    }
+
+    #[test]
+    fn unknown_arguments() {
+        assert_eq!(parse(&["--fullscreen"]).unwrap_err(), "unknown argument '--fullscreen'");
+        assert_eq!(parse(&["scene.ron"]).unwrap_err(), "unknown argument 'scene.ron'");
+        assert!(parse(&["-h"]).unwrap().help);
+    }
+}
@@ -0,0 +1,1354 @@
+// A CPU port of shader.comp. Every function here mirrors the GLSL function of the same name, so
+// keep the two in sync. Besides being a fallback for machines without Vulkan, this is the ground
+// truth shader changes get checked against.
+
+use std::f32::consts::PI;
+
+use cgmath::{ElementWise, InnerSpace, Matrix3, Vector2, Vector3};
+use image::RgbaImage;
+
+use crate::bvh::{self, Bvh, Primitive};
+
+use crate::camera::{Camera, Projection};
+use crate::environment::{Background, Environment, HdrImage};
+use crate::light::{Light, LightType};
+use crate::material::{Material, Pattern};
+use crate::mesh::Mesh;
+use crate::scene::{RenderMode, RenderSettings, Sampling, Scene, Stereo};
+use crate::shape::{Plane, Shape};
+use crate::sphere::Sphere;
+
+// Same (not quite max) float the shader uses
+pub(crate) const MAX_FLOAT: f32 = 340282350000.0;
+
+const RAY_RECURSION_DEPTH: usize = 4;
+
+const PATH_BOUNCES: usize = 5;
+
+pub struct CpuTracer<'a> {
+    spheres: &'a [Sphere],
+    // Every mesh's triangles in one list, in the same order the shader's triangle buffer has them
+    triangles: Vec<(&'a Mesh, [u32; 3])>,
+    lights: &'a [Light],
+    materials: &'a [Material],
+    planes: &'a [Plane],
+    shapes: &'a [Shape],
+    textures: &'a [RgbaImage],
+    maps: &'a [RgbaImage],
+    environment: &'a Environment,
+    render: RenderSettings,
+    bvh: Bvh,
+}
+
+/// What `closest_intersection` hit, along with anything `surface_at` needs to know about it
+#[derive(Copy, Clone)]
+pub enum Hit {
+    Sphere(usize),
+    /// `u` and `v` are the barycentric weights of the second and third corner
+    Triangle { index: usize, u: f32, v: f32 },
+    Plane(usize),
+    Shape(usize),
+}
+
+/// What a ray sees at the point it hit
+pub struct Surface {
+    pub normal: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub specular: f32,
+    pub reflective: f32,
+    pub transparency: f32,
+    pub ior: f32,
+    /// The ray hit the back of the surface, so it's on its way out of the object
+    pub inside: bool,
+    pub emission: Vector3<f32>,
+}
+
+/// Light reaching a point, split by what the surface does with it. Diffuse light takes on the
+/// surface's color, specular highlights keep the light's
+#[derive(Copy, Clone)]
+pub struct Lighting {
+    pub diffuse: Vector3<f32>,
+    pub specular: Vector3<f32>,
+}
+
+/// A ray `trace_ray` still has to follow, and how much of what it sees makes it into the pixel
+struct PendingRay {
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    weight: Vector3<f32>,
+    t_min: f32,
+    t_max: f32,
+    depth: usize,
+}
+
+impl<'a> CpuTracer<'a> {
+    pub fn new(scene: &'a Scene) -> Self {
+        Self {
+            spheres: &scene.spheres,
+            triangles: scene.triangles().collect(),
+            lights: &scene.lights,
+            materials: &scene.materials,
+            planes: &scene.planes,
+            shapes: &scene.shapes,
+            textures: &scene.textures,
+            maps: &scene.maps,
+            environment: &scene.environment,
+            render: scene.render,
+            bvh: Bvh::build(scene),
+        }
+    }
+
+    /// Traces the scene as seen from `camera` into tightly packed 8 bit RGB, row by row from the
+    /// top. Scenes that accumulate classic frames get averaged over `frames` of them the way the
+    /// shader accumulates them, other scenes only need the one
+    pub fn render(&self, camera: &Camera, width: usize, height: usize, frames: u32) -> Vec<u8> {
+        self.render_frames(camera, width, height, frames, RenderMode::Classic)
+    }
+
+    /// Same as `render`, but path traced, which always averages over `frames` frames
+    pub fn render_path_traced(&self, camera: &Camera, width: usize, height: usize, frames: u32) -> Vec<u8> {
+        self.render_frames(camera, width, height, frames, RenderMode::PathTraced)
+    }
+
+    // See main in the shader
+    fn render_frames(&self, camera: &Camera, width: usize, height: usize, frames: u32, mode: RenderMode) -> Vec<u8> {
+        let rotation = view_rotation(camera);
+        let frames = if self.render.accumulates(mode) { frames } else { 1 };
+        let sampling = self.render.sampling(mode);
+        let samples = self.render.samples;
+
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let (eye, view_pixel, view_size) = stereo_view(self.render.stereo, x, y, width, height);
+                let mut sum = Vector3::new(0.0, 0.0, 0.0);
+                for frame in 0..frames {
+                    let mut rng = pcg_hash(x as u32 + y as u32 * width as u32) ^ pcg_hash(frame);
+                    let mut pixel_color = Vector3::new(0.0, 0.0, 0.0);
+                    for i in 0..samples {
+                        let offset = sample_offset(sampling, i, samples, frame, &mut rng);
+                        let (o, d) = canvas_to_viewport(camera, view_pixel + offset, view_size);
+                        let (mut o, mut d) = eye_ray(camera, eye, o, d);
+                        if camera.aperture > 0.0 {
+                            let lens = (camera.aperture * sample_disk(&mut rng)).extend(0.0);
+                            o += lens;
+                            d -= lens / camera.focus_distance;
+                        }
+                        let o = camera.position + rotation * o;
+                        let d = rotation * d;
+                        pixel_color += match mode {
+                            RenderMode::Classic => self.trace_ray(o, d, camera.near, MAX_FLOAT),
+                            RenderMode::PathTraced => self.trace_path(o, d, camera.near, &mut rng),
+                        };
+                    }
+                    sum += pixel_color / samples as f32;
+                }
+                push_pixel(&mut pixels, sum / frames as f32);
+            }
+        }
+        pixels
+    }
+
+    pub fn trace_ray(&self, o: Vector3<f32>, d: Vector3<f32>, t_min: f32, t_max: f32) -> Vector3<f32> {
+        // See traceRay in the shader for why this is a stack rather than recursion
+        let mut stack = Vec::with_capacity(RAY_RECURSION_DEPTH);
+        stack.push(PendingRay { origin: o, direction: d, weight: Vector3::new(1.0, 1.0, 1.0), t_min, t_max, depth: 0 });
+        let mut color = Vector3::new(0.0, 0.0, 0.0);
+        while let Some(ray) = stack.pop() {
+            let r = ray.direction;
+            let closest = self.closest_intersection(ray.origin, r, ray.t_min, ray.t_max);
+            let hit_t = closest.map_or(MAX_FLOAT, |(_, t)| t);
+            if let Some((light, _)) = self.closest_area_light(ray.origin, r, ray.t_min, hit_t.min(ray.t_max)) {
+                color += ray.weight.mul_element_wise(light_color(&self.lights[light]));
+                continue;
+            }
+            let (hit, t) = match closest {
+                Some(hit) => hit,
+                // Rays that don't hit anything see the background
+                None => {
+                    color += ray.weight.mul_element_wise(self.background(r, ray.depth > 0));
+                    continue;
+                }
+            };
+
+            let p = ray.origin + t * r;
+            let surface = self.surface_at(hit, p, r);
+            let n = surface.normal;
+            color += ray.weight.mul_element_wise(surface.emission);
+
+            // Whatever light isn't mirrored or let through is what the surface itself looks like
+            let opacity = (1.0 - surface.reflective) * (1.0 - surface.transparency);
+            if opacity > 0.0 {
+                let lighting = self.compute_lighting(p, n, -r, surface.specular);
+                let local_color = surface.color.mul_element_wise(lighting.diffuse) + lighting.specular;
+                color += (ray.weight * opacity).mul_element_wise(local_color);
+            }
+
+            if ray.depth + 1 >= RAY_RECURSION_DEPTH {
+                continue;
+            }
+
+            let bounce_min = if surface.transparency > 0.0 { 0.001 } else { 0.1 };
+            let mut reflected = surface.reflective;
+            let dielectric = (1.0 - surface.reflective) * surface.transparency;
+            if dielectric > 0.0 {
+                let i = r.normalize();
+                let facing = if n.dot(i) < 0.0 { n } else { -n };
+                let eta = if surface.inside { surface.ior } else { 1.0 / surface.ior };
+                let f = fresnel(-i.dot(facing), eta);
+                reflected += dielectric * f;
+                // Total internal reflection leaves nothing to refract
+                if f < 1.0 {
+                    stack.push(PendingRay {
+                        origin: p,
+                        direction: refract(i, facing, eta),
+                        // What's seen through colored glass takes on its color
+                        weight: (ray.weight * dielectric * (1.0 - f)).mul_element_wise(surface.color),
+                        t_min: bounce_min,
+                        t_max: MAX_FLOAT,
+                        depth: ray.depth + 1,
+                    });
+                }
+            }
+            if reflected > 0.0 {
+                stack.push(PendingRay {
+                    origin: p,
+                    direction: reflect_ray(-r, n),
+                    weight: ray.weight * reflected,
+                    t_min: bounce_min,
+                    t_max: MAX_FLOAT,
+                    depth: ray.depth + 1,
+                });
+            }
+            // The shader's stack is a fixed size array
+            debug_assert!(stack.len() <= RAY_RECURSION_DEPTH);
+        }
+
+        color
+    }
+
+    /// See tracePath in the shader
+    pub fn trace_path(&self, o: Vector3<f32>, d: Vector3<f32>, mut t_min: f32, rng: &mut u32) -> Vector3<f32> {
+        let mut radiance = Vector3::new(0.0, 0.0, 0.0);
+        let mut throughput = Vector3::new(1.0, 1.0, 1.0);
+        let mut p = o;
+        let mut r = d;
+        let mut diffuse_bounce = false;
+        for bounce in 0..PATH_BOUNCES {
+            let closest = self.closest_intersection(p, r, t_min, MAX_FLOAT);
+            let hit_t = closest.map_or(MAX_FLOAT, |(_, t)| t);
+            if let Some((light, _)) = self.closest_area_light(p, r, t_min, hit_t) {
+                if !diffuse_bounce {
+                    radiance += throughput.mul_element_wise(light_color(&self.lights[light]));
+                }
+                break;
+            }
+            let (hit, t) = match closest {
+                Some(hit) => hit,
+                None => {
+                    let ambient = if diffuse_bounce { self.ambient_lighting() } else { Vector3::new(0.0, 0.0, 0.0) };
+                    radiance += throughput.mul_element_wise(ambient + self.background(r, bounce > 0));
+                    break;
+                }
+            };
+
+            p += t * r;
+            let surface = self.surface_at(hit, p, r);
+            let n = surface.normal;
+            radiance += throughput.mul_element_wise(surface.emission);
+
+            let choice = random_float(rng);
+            let dielectric = (1.0 - surface.reflective) * surface.transparency;
+            diffuse_bounce = false;
+            if choice < surface.reflective {
+                r = reflect_ray(-r, n);
+            } else if choice < surface.reflective + dielectric {
+                let i = r.normalize();
+                let facing = if n.dot(i) < 0.0 { n } else { -n };
+                let eta = if surface.inside { surface.ior } else { 1.0 / surface.ior };
+                if random_float(rng) < fresnel(-i.dot(facing), eta) {
+                    r = reflect_ray(-r, n);
+                } else {
+                    r = refract(i, facing, eta);
+                    throughput = throughput.mul_element_wise(surface.color);
+                }
+            } else {
+                let direct = self.direct_lighting(p, n, -r, surface.specular, Some(&mut *rng));
+                radiance += throughput.mul_element_wise(surface.color.mul_element_wise(direct.diffuse) + direct.specular);
+                throughput = throughput.mul_element_wise(surface.color);
+                r = cosine_sample_hemisphere(n, rng);
+                diffuse_bounce = true;
+            }
+            t_min = 0.001;
+        }
+        radiance
+    }
+
+    /// Closest object hit within `t_min..=t_max` and the distance to it
+    pub fn closest_intersection(&self, p: Vector3<f32>, d: Vector3<f32>, t_min: f32, t_max: f32) -> Option<(Hit, f32)> {
+        let mut closest_t = MAX_FLOAT;
+        let mut closest = None;
+
+        for (i, plane) in self.planes.iter().enumerate() {
+            let t = intersect_ray_plane(p, d, plane.point, plane.normal);
+            if (t_min <= t && t <= t_max) && t < closest_t {
+                closest_t = t;
+                closest = Some(Hit::Plane(i));
+            }
+        }
+
+        // See Bvh::build, there's nothing to walk
+        if self.bvh.primitives.is_empty() {
+            return closest.map(|hit| (hit, closest_t));
+        }
+
+        let inv_d = Vector3::new(1.0 / d.x, 1.0 / d.y, 1.0 / d.z);
+
+        // Nodes still to visit, along with where the ray enters them
+        let mut stack = Vec::new();
+        let root_distance = bvh::intersect_ray_box(p, inv_d, &self.bvh.nodes[0].bounds, t_min, t_max);
+        if root_distance < MAX_FLOAT {
+            stack.push((0, root_distance));
+        }
+
+        while let Some((node_index, distance)) = stack.pop() {
+            // Something closer may have turned up since this node was pushed
+            if distance > closest_t {
+                continue;
+            }
+            let node = &self.bvh.nodes[node_index];
+
+            if node.count == 0 {
+                let t_limit = t_max.min(closest_t);
+                let mut near = node_index + 1;
+                let mut far = node.offset as usize;
+                let mut near_distance = bvh::intersect_ray_box(p, inv_d, &self.bvh.nodes[near].bounds, t_min, t_limit);
+                let mut far_distance = bvh::intersect_ray_box(p, inv_d, &self.bvh.nodes[far].bounds, t_min, t_limit);
+                if far_distance < near_distance {
+                    std::mem::swap(&mut near, &mut far);
+                    std::mem::swap(&mut near_distance, &mut far_distance);
+                }
+
+                // Push the farther child first so the nearer one gets visited first
+                if far_distance < MAX_FLOAT {
+                    stack.push((far, far_distance));
+                }
+                if near_distance < MAX_FLOAT {
+                    stack.push((near, near_distance));
+                }
+                continue;
+            }
+
+            let leaf = node.offset as usize..(node.offset + node.count) as usize;
+            for &primitive in &self.bvh.primitives[leaf] {
+                match primitive {
+                    Primitive::Sphere(i) => {
+                        let sphere = &self.spheres[i];
+                        let (t1, t2) = intersect_ray_sphere(p, d, sphere.center.truncate(), sphere.radius);
+
+                        if (t_min <= t1 && t1 <= t_max) && t1 < closest_t {
+                            closest_t = t1;
+                            closest = Some(Hit::Sphere(i));
+                        }
+                        if (t_min <= t2 && t2 <= t_max) && t2 < closest_t {
+                            closest_t = t2;
+                            closest = Some(Hit::Sphere(i));
+                        }
+                    }
+                    Primitive::Shape(i) => {
+                        let (t1, t2) = intersect_ray_shape(p, d, &self.shapes[i]);
+
+                        if (t_min <= t1 && t1 <= t_max) && t1 < closest_t {
+                            closest_t = t1;
+                            closest = Some(Hit::Shape(i));
+                        }
+                        if (t_min <= t2 && t2 <= t_max) && t2 < closest_t {
+                            closest_t = t2;
+                            closest = Some(Hit::Shape(i));
+                        }
+                    }
+                    Primitive::Triangle(i) => {
+                        let (mesh, [a, b, c]) = self.triangles[i];
+                        let (t, u, v) = intersect_ray_triangle(
+                            p, d,
+                            mesh.positions[a as usize],
+                            mesh.positions[b as usize],
+                            mesh.positions[c as usize],
+                        );
+
+                        if (t_min <= t && t <= t_max) && t < closest_t {
+                            closest_t = t;
+                            closest = Some(Hit::Triangle { index: i, u, v });
+                        }
+                    }
+                }
+            }
+        }
+        closest.map(|hit| (hit, closest_t))
+    }
+
+    /// `p` is the hit point and `d` the direction of the ray that hit it
+    pub fn surface_at(&self, hit: Hit, p: Vector3<f32>, d: Vector3<f32>) -> Surface {
+        match hit {
+            Hit::Triangle { index, u, v } => {
+                let (mesh, [a, b, c]) = self.triangles[index];
+                let (a, b, c) = (a as usize, b as usize, c as usize);
+
+                // Interpolate the vertex normals and texture coordinates across the triangle
+                let mut n = ((1.0 - u - v) * mesh.normals[a] + u * mesh.normals[b] + v * mesh.normals[c]).normalize();
+                let uv = (1.0 - u - v) * mesh.uvs[a] + u * mesh.uvs[b] + v * mesh.uvs[c];
+
+                // Meshes don't have to be closed, so make the normal face whoever is looking at it.
+                // Flipped before the tangents get built around it
+                let inside = n.dot(d) > 0.0;
+                if inside {
+                    n = -n;
+                }
+
+                // The directions u and v grow in across the triangle
+                let edge1 = mesh.positions[b] - mesh.positions[a];
+                let edge2 = mesh.positions[c] - mesh.positions[a];
+                let uv1 = mesh.uvs[b] - mesh.uvs[a];
+                let uv2 = mesh.uvs[c] - mesh.uvs[a];
+                let det = uv1.x * uv2.y - uv2.x * uv1.y;
+                let (t, b) = if det.abs() > 0.0000001 {
+                    ((edge1 * uv2.y - edge2 * uv1.y) / det, (edge2 * uv1.x - edge1 * uv2.x) / det)
+                } else {
+                    // No texture coordinates to go by
+                    planar_tangents(n)
+                };
+                self.material_surface(n, inside, mesh.material, uv, t, b)
+            }
+            Hit::Plane(index) => {
+                let plane = &self.planes[index];
+                // Planes have two sides, both facing whoever looks at them
+                let mut n = plane.normal;
+                let inside = n.dot(d) > 0.0;
+                if inside {
+                    n = -n;
+                }
+                let (t, b) = planar_tangents(n);
+                self.material_surface(n, inside, plane.material, planar_uv(p, n), t, b)
+            }
+            Hit::Shape(index) => {
+                let shape = &self.shapes[index];
+                let mut n = shape_normal(shape, p);
+                let inside = n.dot(d) > 0.0;
+                // Same goes for disks. Boxes and cylinders are closed, so only glass ones get seen
+                // from the inside
+                if matches!(shape, Shape::Disk(_)) && inside {
+                    n = -n;
+                }
+                let (t, b) = planar_tangents(n);
+                self.material_surface(n, inside, shape.material(), planar_uv(p, n), t, b)
+            }
+            Hit::Sphere(index) => {
+                let sphere = &self.spheres[index];
+                let n = (p - sphere.center.truncate()).normalize();
+                let (t, b) = sphere_tangents(n);
+                self.material_surface(n, n.dot(d) > 0.0, sphere.material, sphere_uv(n), t, b)
+            }
+        }
+    }
+
+    /// `t` and `b` are the directions u and v grow in along the surface, see materialSurface in
+    /// the shader
+    pub fn material_surface(
+        &self,
+        n: Vector3<f32>,
+        inside: bool,
+        material: usize,
+        uv: Vector2<f32>,
+        t: Vector3<f32>,
+        b: Vector3<f32>,
+    ) -> Surface {
+        let material = &self.materials[material];
+        let n = self.mapped_normal(material, n, uv, t, b);
+        let base = Vector3::new(material.color[0], material.color[1], material.color[2]);
+        let mix = pattern_mix(material.pattern, uv / material.pattern_scale);
+        let mut color = base + (Vector3::from(material.pattern_color) - base) * mix;
+        if let Some(texture) = material.texture {
+            color = color.mul_element_wise(srgb_texture_lod(&self.textures[texture], uv));
+        }
+        Surface {
+            normal: n,
+            color,
+            specular: material.specular,
+            reflective: material.reflective,
+            transparency: material.transparency,
+            ior: material.ior,
+            inside,
+            emission: material.emission.into(),
+        }
+    }
+
+    /// See mappedNormal in the shader
+    pub fn mapped_normal(&self, material: &Material, n: Vector3<f32>, uv: Vector2<f32>, t: Vector3<f32>, b: Vector3<f32>) -> Vector3<f32> {
+        if material.normal_map.is_none() && material.bump_map.is_none() {
+            return n;
+        }
+        // Make the tangents perpendicular to n and each other
+        let mut t = (t - n * n.dot(t)).normalize();
+        let mut b = (b - n * n.dot(b) - t * t.dot(b)).normalize();
+
+        let mut n = n;
+        if let Some(bump_map) = material.bump_map {
+            let texture = &self.maps[bump_map];
+            let texel = Vector2::new(1.0 / texture.width() as f32, 1.0 / texture.height() as f32);
+            let height = texture_lod(texture, uv).x;
+            let dhdu = texture_lod(texture, uv + Vector2::new(texel.x, 0.0)).x - height;
+            let dhdv = texture_lod(texture, uv + Vector2::new(0.0, texel.y)).x - height;
+            n = (n - material.bump_strength * (dhdu * t + dhdv * b)).normalize();
+            // A normal map on top tilts the bumped n, so the tangents have to follow it
+            t = (t - n * n.dot(t)).normalize();
+            b = (b - n * n.dot(b) - t * t.dot(b)).normalize();
+        }
+        if let Some(normal_map) = material.normal_map {
+            let m = texture_lod(&self.maps[normal_map], uv) * 2.0 - Vector3::new(1.0, 1.0, 1.0);
+            n = (m.x * t - m.y * b + m.z * n).normalize();
+        }
+        n
+    }
+
+    pub fn compute_lighting(&self, p: Vector3<f32>, n: Vector3<f32>, v: Vector3<f32>, specularity: f32) -> Lighting {
+        let mut lighting = self.direct_lighting(p, n, v, specularity, None);
+        lighting.diffuse += self.ambient_lighting();
+        lighting
+    }
+
+    pub fn ambient_lighting(&self) -> Vector3<f32> {
+        self.lights.iter()
+            .filter(|light| matches!(light.light_type, LightType::Ambient))
+            .fold(Vector3::new(0.0, 0.0, 0.0), |intensity, light| intensity + light_color(light))
+    }
+
+    /// How far in front of `camera` whatever is straight ahead of it is, in the middle of the view
+    /// whatever the projection. Setting the focus distance to it brings that into focus
+    pub fn focus_depth(&self, camera: &Camera) -> Option<f32> {
+        let d = view_rotation(camera) * Vector3::unit_z();
+        self.closest_intersection(camera.position, d, camera.near, MAX_FLOAT).map(|(_, t)| t)
+    }
+
+    /// See background in the shader
+    pub fn background(&self, d: Vector3<f32>, secondary: bool) -> Vector3<f32> {
+        if secondary && !self.environment.lighting {
+            return Vector3::new(0.0, 0.0, 0.0);
+        }
+        match &self.environment.background {
+            Background::Solid(color) => (*color).into(),
+            Background::Gradient { top, bottom } => {
+                let f = d.normalize().y * 0.5 + 0.5;
+                Vector3::from(*bottom) * (1.0 - f) + Vector3::from(*top) * f
+            }
+            Background::Image { image, intensity } => environment_lod(image, sphere_uv(d.normalize())) * *intensity,
+        }
+    }
+
+    /// Light reaching `p` straight from every light but ambient ones. Area lights get a single
+    /// random point when there's an `rng` to pick it with, see directLighting in the shader
+    pub fn direct_lighting(&self, p: Vector3<f32>, n: Vector3<f32>, v: Vector3<f32>, specularity: f32, mut rng: Option<&mut u32>) -> Lighting {
+        let mut lighting = Lighting { diffuse: Vector3::new(0.0, 0.0, 0.0), specular: Vector3::new(0.0, 0.0, 0.0) };
+        for (i, light) in self.lights.iter().enumerate() {
+            match light.light_type {
+                LightType::Ambient => {}
+                LightType::Point => {
+                    let l = light.position.truncate() - p;
+                    self.light_along(&mut lighting, p, n, v, specularity, l, 1.0, light_color(light) * attenuation(light, l.magnitude()));
+                }
+                LightType::Directional =>
+                    self.light_along(&mut lighting, p, n, v, specularity, -light.direction.truncate(), MAX_FLOAT, light_color(light)),
+                LightType::Spot => {
+                    let l = light.position.truncate() - p;
+                    let falloff = spot_falloff(light, l) * attenuation(light, l.magnitude());
+                    if falloff > 0.0 {
+                        self.light_along(&mut lighting, p, n, v, specularity, l, 1.0, light_color(light) * falloff);
+                    }
+                }
+                LightType::Sphere | LightType::Rectangle => match rng.as_deref_mut() {
+                    Some(rng) => {
+                        let uv = (random_float(rng), random_float(rng));
+                        let l = self.area_light_point(i, uv) - p;
+                        self.light_along(&mut lighting, p, n, v, specularity, l, 1.0, light_color(light) * attenuation(light, l.magnitude()));
+                    }
+                    None => {
+                        let share = light_color(light) / light.samples as f32;
+                        for s in 0..light.samples {
+                            let uv = ((s as f32 + 0.5) / light.samples as f32, (s as f32 * 0.618034).fract());
+                            let l = self.area_light_point(i, uv) - p;
+                            self.light_along(&mut lighting, p, n, v, specularity, l, 1.0, share * attenuation(light, l.magnitude()));
+                        }
+                    }
+                },
+            }
+        }
+        lighting
+    }
+
+    /// Adds the diffuse and specular light from a light of the given intensity in direction `l` to
+    /// `lighting`, unless something is in the way within `t_max`. Each color channel is lit
+    /// separately
+    #[allow(clippy::too_many_arguments)]
+    pub fn light_along(&self, lighting: &mut Lighting, p: Vector3<f32>, n: Vector3<f32>, v: Vector3<f32>, specularity: f32, l: Vector3<f32>, t_max: f32, intensity: Vector3<f32>) {
+        // Shadow check
+        if self.closest_intersection(p, l, 0.001, t_max).is_some() {
+            return;
+        }
+
+        // Diffuse lighting
+        let n_dot_l = n.dot(l);
+        if n_dot_l > 0.0 {
+            lighting.diffuse += intensity * (n_dot_l / (n.magnitude() * l.magnitude()));
+        }
+
+        // Specular lighting
+        if specularity != -1.0 {
+            let r = reflect_ray(l, n);
+            let r_dot_v = r.dot(v);
+            if r_dot_v > 0.0 { // Don't add negative light intensity
+                lighting.specular += intensity * (r_dot_v / (r.magnitude() * v.magnitude())).powf(specularity);
+            }
+        }
+    }
+
+    /// See areaLightPoint in the shader
+    pub fn area_light_point(&self, light: usize, (u, v): (f32, f32)) -> Vector3<f32> {
+        let light = &self.lights[light];
+        let center = light.position.truncate();
+        if let LightType::Rectangle = light.light_type {
+            return center + (u - 0.5) * light.u.truncate() + (v - 0.5) * light.v.truncate();
+        }
+        let z = 1.0 - 2.0 * u;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+        center + light.radius * Vector3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    /// Closest sphere or rectangle light within `t_min..=t_max` and the distance to it
+    pub fn closest_area_light(&self, p: Vector3<f32>, d: Vector3<f32>, t_min: f32, t_max: f32) -> Option<(usize, f32)> {
+        let mut closest_t = MAX_FLOAT;
+        let mut closest = None;
+        for (i, light) in self.lights.iter().enumerate() {
+            let center = light.position.truncate();
+            let t = match light.light_type {
+                LightType::Sphere => {
+                    let (t1, t2) = intersect_ray_sphere(p, d, center, light.radius);
+                    if t_min <= t2 && t2 <= t_max { t2 } else { t1 }
+                }
+                LightType::Rectangle => {
+                    let (u, v) = (light.u.truncate(), light.v.truncate());
+                    let t = intersect_ray_plane(p, d, center, u.cross(v));
+                    // Within the rectangle when the hit point is less than half an edge from the
+                    // center along both edges
+                    let offset = p + t * d - center;
+                    if (offset.dot(u) / u.dot(u)).abs() > 0.5 || (offset.dot(v) / v.dot(v)).abs() > 0.5 {
+                        MAX_FLOAT
+                    } else {
+                        t
+                    }
+                }
+                _ => continue,
+            };
+            if (t_min <= t && t <= t_max) && t < closest_t {
+                closest_t = t;
+                closest = Some(i);
+            }
+        }
+        closest.map(|light| (light, closest_t))
+    }
+}
+
+/// What a light gives off, per color channel
+pub fn light_color(light: &Light) -> Vector3<f32> {
+    Vector3::from(light.color) * light.intensity
+}
+
+/// See spotFalloff in the shader
+pub fn spot_falloff(light: &Light, l: Vector3<f32>) -> f32 {
+    let cosine = (-l).normalize().dot(light.direction.truncate().normalize());
+    if cosine >= light.inner_cos {
+        return 1.0;
+    }
+    if cosine <= light.outer_cos {
+        return 0.0;
+    }
+    let t = (cosine - light.outer_cos) / (light.inner_cos - light.outer_cos);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// See attenuation in the shader
+pub fn attenuation(light: &Light, distance: f32) -> f32 {
+    if light.range > 0.0 && distance > light.range {
+        return 0.0;
+    }
+    let [constant, linear, quadratic] = light.attenuation;
+    1.0 / (constant + linear * distance + quadratic * distance * distance)
+}
+
+/// See sphereUV in the shader
+pub fn sphere_uv(n: Vector3<f32>) -> Vector2<f32> {
+    Vector2::new(0.5 + n.z.atan2(n.x) / (2.0 * PI), 0.5 - n.y.clamp(-1.0, 1.0).asin() / PI)
+}
+
+/// See sphereTangents in the shader
+pub fn sphere_tangents(n: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let mut t = Vector3::new(-n.z, 0.0, n.x);
+    if t.dot(t) < 0.0000001 {
+        t = Vector3::unit_x();
+    }
+    (t, n.cross(t))
+}
+
+/// See planarTangents in the shader
+pub fn planar_tangents(n: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let a = Vector3::new(n.x.abs(), n.y.abs(), n.z.abs());
+    if a.x >= a.y && a.x >= a.z {
+        (Vector3::unit_z(), Vector3::unit_y())
+    } else if a.y >= a.z {
+        (Vector3::unit_x(), Vector3::unit_z())
+    } else {
+        (Vector3::unit_x(), Vector3::unit_y())
+    }
+}
+
+/// See planarUV in the shader
+pub fn planar_uv(p: Vector3<f32>, n: Vector3<f32>) -> Vector2<f32> {
+    let a = Vector3::new(n.x.abs(), n.y.abs(), n.z.abs());
+    if a.x >= a.y && a.x >= a.z {
+        return Vector2::new(p.z, p.y);
+    }
+    if a.y >= a.z {
+        return Vector2::new(p.x, p.z);
+    }
+    Vector2::new(p.x, p.y)
+}
+
+/// See patternMix in the shader
+pub fn pattern_mix(pattern: Pattern, uv: Vector2<f32>) -> f32 {
+    match pattern {
+        Pattern::Solid => 0.0,
+        Pattern::Checker => ((uv.x.floor() as i32 + uv.y.floor() as i32) & 1) as f32,
+        Pattern::Stripes => (uv.x.floor() as i32 & 1) as f32,
+        Pattern::Noise => value_noise(uv),
+    }
+}
+
+/// See valueNoise in the shader
+pub fn value_noise(uv: Vector2<f32>) -> f32 {
+    let cell = (uv.x.floor() as i32, uv.y.floor() as i32);
+    let f = Vector2::new(uv.x - cell.0 as f32, uv.y - cell.1 as f32);
+    let f = f.mul_element_wise(Vector2::new(3.0, 3.0) - 2.0 * f).mul_element_wise(f);
+    let mut corners = [0.0; 4];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        let (x, y) = (cell.0 + (i as i32 & 1), cell.1 + (i as i32 >> 1));
+        let mut state = pcg_hash(x as u32) ^ y as u32;
+        *corner = random_float(&mut state);
+    }
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    lerp(lerp(corners[0], corners[1], f.x), lerp(corners[2], corners[3], f.x), f.y)
+}
+
+/// GLSL's textureLod at level 0 through a linear, repeating sampler, reading the texture the way
+/// the GPU reads an rgba8 unorm image
+pub fn texture_lod(texture: &RgbaImage, uv: Vector2<f32>) -> Vector3<f32> {
+    let (width, height) = texture.dimensions();
+    bilinear(width, height, uv, false, |x, y| {
+        let pixel = texture.get_pixel(x, y);
+        Vector3::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 255.0
+    })
+}
+
+/// `texture_lod` for an rgba8 sRGB image, which the GPU turns linear before blending texels
+pub fn srgb_texture_lod(texture: &RgbaImage, uv: Vector2<f32>) -> Vector3<f32> {
+    let (width, height) = texture.dimensions();
+    bilinear(width, height, uv, false, |x, y| {
+        let pixel = texture.get_pixel(x, y);
+        Vector3::new(srgb_to_linear(pixel[0]), srgb_to_linear(pixel[1]), srgb_to_linear(pixel[2]))
+    })
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// `texture_lod` for the float image of an environment, which wraps around in u but stops at the
+/// top and bottom edges like the environment sampler does
+pub fn environment_lod(image: &HdrImage, uv: Vector2<f32>) -> Vector3<f32> {
+    bilinear(image.width(), image.height(), uv, true, |x, y| Vector3::from(image.get_pixel(x, y).0))
+}
+
+// Blends the four texels around uv, wrapping around the edges. With clamp_v rows past the top and
+// bottom edges repeat the edge row instead
+fn bilinear(width: u32, height: u32, uv: Vector2<f32>, clamp_v: bool, texel: impl Fn(u32, u32) -> Vector3<f32>) -> Vector3<f32> {
+    // Texel centers sit half a texel in from the edges
+    let x = uv.x * width as f32 - 0.5;
+    let y = uv.y * height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let texel = |x: f32, y: f32| {
+        let y = if clamp_v { (y as i64).clamp(0, height as i64 - 1) } else { (y as i64).rem_euclid(height as i64) };
+        texel((x as i64).rem_euclid(width as i64) as u32, y as u32)
+    };
+    let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1.0, y0) * fx;
+    let bottom = texel(x0, y0 + 1.0) * (1.0 - fx) + texel(x0 + 1.0, y0 + 1.0) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+pub fn reflect_ray(r: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    2.0 * n * n.dot(r) - r
+}
+
+/// GLSL's refract. `i` and `n` have to be normalized, and a zero vector comes back on total
+/// internal reflection
+pub fn refract(i: Vector3<f32>, n: Vector3<f32>, eta: f32) -> Vector3<f32> {
+    let n_dot_i = n.dot(i);
+    let k = 1.0 - eta * eta * (1.0 - n_dot_i * n_dot_i);
+    if k < 0.0 {
+        return Vector3::new(0.0, 0.0, 0.0);
+    }
+    eta * i - (eta * n_dot_i + k.sqrt()) * n
+}
+
+/// See fresnel in the shader
+pub fn fresnel(cosine: f32, eta: f32) -> f32 {
+    let mut cosine = cosine;
+    // Going into a less dense medium it's the angle on the far side that counts
+    if eta > 1.0 {
+        let sin2 = eta * eta * (1.0 - cosine * cosine);
+        if sin2 > 1.0 {
+            return 1.0;
+        }
+        cosine = (1.0 - sin2).sqrt();
+    }
+    let r0 = ((1.0 - eta) / (1.0 + eta)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
+}
+
+// mat3(camera.rotation) in the shader
+fn view_rotation(camera: &Camera) -> Matrix3<f32> {
+    let m = camera.calc_rotation_matrix();
+    Matrix3::from_cols(m.x.truncate(), m.y.truncate(), m.z.truncate())
+}
+
+// Same conversion imageStore does for an rgba8 image. max and min rather than clamp, which passes
+// NaN through instead of turning it into 0 the way the GPU does
+#[allow(clippy::manual_clamp)]
+fn push_pixel(pixels: &mut Vec<u8>, color: Vector3<f32>) {
+    pixels.extend(
+        [color.x, color.y, color.z].iter()
+            .map(|c| (c.max(0.0).min(1.0) * 255.0).round() as u8)
+    );
+}
+
+/// Which eye's view pixel `x`, `y` of the image is in, as -1 for the left eye, 1 for the right eye or
+/// 0 for a mono image, followed by where in that view it is and how big the view is. See
+/// stereoView in the shader
+pub fn stereo_view(stereo: Stereo, x: usize, y: usize, width: usize, height: usize) -> (f32, Vector2<f32>, Vector2<f32>) {
+    let pixel = Vector2::new(x as f32, y as f32);
+    match stereo {
+        Stereo::Mono => (0.0, pixel, Vector2::new(width as f32, height as f32)),
+        Stereo::SideBySide => {
+            let left = width / 2;
+            if x < left {
+                (-1.0, pixel, Vector2::new(left as f32, height as f32))
+            } else {
+                (1.0, pixel - Vector2::new(left as f32, 0.0), Vector2::new((width - left) as f32, height as f32))
+            }
+        }
+        Stereo::TopBottom => {
+            let top = height / 2;
+            if y < top {
+                (-1.0, pixel, Vector2::new(width as f32, top as f32))
+            } else {
+                (1.0, pixel - Vector2::new(0.0, top as f32), Vector2::new(width as f32, (height - top) as f32))
+            }
+        }
+    }
+}
+
+/// Moves the camera relative ray `o`, `d` over to the `eye` from [`stereo_view`], turned in so the
+/// two eyes' rays meet at the convergence depth. See eyeRay in the shader
+pub fn eye_ray(camera: &Camera, eye: f32, o: Vector3<f32>, d: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    if eye == 0.0 {
+        return (o, d);
+    }
+    let mut side = Vector3::unit_x();
+    let around = Vector2::new(d.z, -d.x);
+    if camera.projection == Projection::Equirectangular && around.magnitude() > 0.0 {
+        side = Vector3::new(around.x, 0.0, around.y) / around.magnitude();
+    }
+    let shift = side * eye * camera.eye_separation / 2.0;
+    (o + shift, d - shift / camera.convergence)
+}
+
+/// `pixel` is in the coordinates of a view `view_size` pixels big, with whole numbers at the top
+/// left corner of each pixel. Returns where the ray through it starts and which way it goes,
+/// relative to the camera. See canvasToViewport in the shader
+pub fn canvas_to_viewport(camera: &Camera, pixel: Vector2<f32>, view_size: Vector2<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let (width, height) = (view_size.x, view_size.y);
+    let x = pixel.x - (width / 2.0);
+    let y = pixel.y - (height / 2.0);
+    let aspect = if camera.aspect > 0.0 { camera.aspect } else { width / height };
+    let origin = Vector3::new(0.0, 0.0, 0.0);
+
+    match camera.projection {
+        Projection::Orthographic => {
+            let h = camera.ortho_height;
+            let origin = Vector3::new(x * (h * aspect / width), -(y * (h / height)), 0.0);
+            (origin, Vector3::unit_z())
+        }
+        Projection::Fisheye => {
+            let p = Vector2::new(x * (2.0 * aspect / width), -(y * (2.0 / height)));
+            let r = p.magnitude();
+            let theta = (r * camera.fov.0 / 2.0).min(PI);
+            let side = if r > 0.0 { p / r * theta.sin() } else { Vector2::new(0.0, 0.0) };
+            (origin, side.extend(theta.cos()))
+        }
+        Projection::Equirectangular => {
+            let longitude = x / width * 2.0 * PI;
+            let latitude = -(y / height) * PI;
+            let d = Vector3::new(latitude.cos() * longitude.sin(), latitude.sin(), latitude.cos() * longitude.cos());
+            (origin, d)
+        }
+        Projection::Perspective => {
+            let viewport_height = 2.0 * (camera.fov.0 / 2.0).tan();
+            let viewport_width = viewport_height * aspect;
+            (origin, Vector3::new(x * (viewport_width / width), -(y * (viewport_height / height)), 1.0))
+        }
+    }
+}
+
+pub fn intersect_ray_sphere(p: Vector3<f32>, d: Vector3<f32>, center: Vector3<f32>, radius: f32) -> (f32, f32) {
+    let co = p - center;
+
+    let a = d.dot(d);
+    let b = 2.0 * co.dot(d);
+    let c = co.dot(co) - (radius * radius);
+
+    let discriminant = (b * b) - (4.0 * a * c);
+
+    if discriminant < 0.0 {
+        return (MAX_FLOAT, MAX_FLOAT);
+    }
+
+    let t1 = (-b + discriminant.sqrt()) / (2.0 * a);
+    let t2 = (-b - discriminant.sqrt()) / (2.0 * a);
+    (t1, t2)
+}
+
+/// Möller-Trumbore. Returns the distance along `d`, or `MAX_FLOAT` on a miss, and the barycentric
+/// weights of `v1` and `v2` at the hit point
+pub fn intersect_ray_triangle(
+    p: Vector3<f32>,
+    d: Vector3<f32>,
+    v0: Vector3<f32>,
+    v1: Vector3<f32>,
+    v2: Vector3<f32>,
+) -> (f32, f32, f32) {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let h = d.cross(edge2);
+    let a = edge1.dot(h);
+
+    // Ray is parallel to the triangle
+    if a.abs() < 0.0000001 {
+        return (MAX_FLOAT, 0.0, 0.0);
+    }
+
+    let f = 1.0 / a;
+    let s = p - v0;
+    let u = f * s.dot(h);
+    if !(0.0..=1.0).contains(&u) {
+        return (MAX_FLOAT, 0.0, 0.0);
+    }
+
+    let q = s.cross(edge1);
+    let v = f * d.dot(q);
+    if v < 0.0 || u + v > 1.0 {
+        return (MAX_FLOAT, 0.0, 0.0);
+    }
+
+    (f * edge2.dot(q), u, v)
+}
+
+/// Distance along `d` to the plane, or `MAX_FLOAT` if `d` runs parallel to it. Can be negative
+/// when the plane is behind `p`
+pub fn intersect_ray_plane(p: Vector3<f32>, d: Vector3<f32>, point: Vector3<f32>, normal: Vector3<f32>) -> f32 {
+    let denominator = normal.dot(d);
+    if denominator.abs() < 0.0000001 {
+        return MAX_FLOAT;
+    }
+    (point - p).dot(normal) / denominator
+}
+
+/// Both places the ray crosses the surface of `shape`, `MAX_FLOAT` for the ones it doesn't
+pub fn intersect_ray_shape(p: Vector3<f32>, d: Vector3<f32>, shape: &Shape) -> (f32, f32) {
+    match shape {
+        Shape::Cuboid(cuboid) => intersect_ray_cuboid(p, d, cuboid.min, cuboid.max),
+        Shape::Disk(disk) => (intersect_ray_disk(p, d, disk.center, disk.normal, disk.radius), MAX_FLOAT),
+        Shape::Cylinder(cylinder) => intersect_ray_cylinder(p, d, cylinder.base, cylinder.axis, cylinder.radius),
+    }
+}
+
+/// Where the ray enters and leaves the box, or `MAX_FLOAT` for both if it misses
+pub fn intersect_ray_cuboid(p: Vector3<f32>, d: Vector3<f32>, min: Vector3<f32>, max: Vector3<f32>) -> (f32, f32) {
+    let inv_d = Vector3::new(1.0 / d.x, 1.0 / d.y, 1.0 / d.z);
+    let t0 = (min - p).mul_element_wise(inv_d);
+    let t1 = (max - p).mul_element_wise(inv_d);
+    let t_enter = t0.x.min(t1.x).max(t0.y.min(t1.y)).max(t0.z.min(t1.z));
+    let t_exit = t0.x.max(t1.x).min(t0.y.max(t1.y)).min(t0.z.max(t1.z));
+    if t_enter > t_exit {
+        return (MAX_FLOAT, MAX_FLOAT);
+    }
+    (t_enter, t_exit)
+}
+
+pub fn intersect_ray_disk(p: Vector3<f32>, d: Vector3<f32>, center: Vector3<f32>, normal: Vector3<f32>, radius: f32) -> f32 {
+    let t = intersect_ray_plane(p, d, center, normal);
+    if t == MAX_FLOAT {
+        return MAX_FLOAT;
+    }
+    let offset = p + t * d - center;
+    if offset.dot(offset) > radius * radius {
+        return MAX_FLOAT;
+    }
+    t
+}
+
+/// Capped cylinder. Returns the nearer crossing first, `MAX_FLOAT` for both on a miss
+pub fn intersect_ray_cylinder(
+    p: Vector3<f32>,
+    d: Vector3<f32>,
+    base: Vector3<f32>,
+    axis: Vector3<f32>,
+    radius: f32,
+) -> (f32, f32) {
+    let height = axis.magnitude();
+    let a_unit = axis / height;
+    let co = p - base;
+    let mut t_near = MAX_FLOAT;
+    let mut t_far = -MAX_FLOAT;
+
+    // With the axis projected out, the side is a circle
+    let side_d = d - d.dot(a_unit) * a_unit;
+    let side_o = co - co.dot(a_unit) * a_unit;
+    let a = side_d.dot(side_d);
+    if a > 0.0000001 {
+        let b = 2.0 * side_o.dot(side_d);
+        let c = side_o.dot(side_o) - (radius * radius);
+        let discriminant = (b * b) - (4.0 * a * c);
+        if discriminant >= 0.0 {
+            let t1 = (-b + discriminant.sqrt()) / (2.0 * a);
+            let t2 = (-b - discriminant.sqrt()) / (2.0 * a);
+            // Only the part of the infinite cylinder between the caps counts
+            for &t in &[t1, t2] {
+                let h = (co + t * d).dot(a_unit);
+                if (0.0..=height).contains(&h) {
+                    t_near = t_near.min(t);
+                    t_far = t_far.max(t);
+                }
+            }
+        }
+    }
+
+    for &center in &[base, base + axis] {
+        let t = intersect_ray_disk(p, d, center, a_unit, radius);
+        if t < MAX_FLOAT {
+            t_near = t_near.min(t);
+            t_far = t_far.max(t);
+        }
+    }
+
+    if t_near == MAX_FLOAT {
+        return (MAX_FLOAT, MAX_FLOAT);
+    }
+    (t_near, t_far)
+}
+
+/// Outward normal of `shape` at `p`, which is assumed to be on its surface
+pub fn shape_normal(shape: &Shape, p: Vector3<f32>) -> Vector3<f32> {
+    match shape {
+        Shape::Cuboid(cuboid) => {
+            // Whichever face p is relatively closest to
+            let center = (cuboid.min + cuboid.max) / 2.0;
+            let half_size = (cuboid.max - cuboid.min) / 2.0;
+            let local = (p - center).div_element_wise(half_size);
+            let distance = Vector3::new(local.x.abs(), local.y.abs(), local.z.abs());
+            if distance.x >= distance.y && distance.x >= distance.z {
+                Vector3::new(glsl_sign(local.x), 0.0, 0.0)
+            } else if distance.y >= distance.z {
+                Vector3::new(0.0, glsl_sign(local.y), 0.0)
+            } else {
+                Vector3::new(0.0, 0.0, glsl_sign(local.z))
+            }
+        }
+        Shape::Disk(disk) => disk.normal,
+        Shape::Cylinder(cylinder) => {
+            // Either the side or one of the caps, whichever p is closest to
+            let height = cylinder.axis.magnitude();
+            let a = cylinder.axis / height;
+            let h = (p - cylinder.base).dot(a);
+            let radial = p - cylinder.base - h * a;
+            let side_distance = (radial.magnitude() - cylinder.radius).abs();
+            if side_distance <= h.abs() && side_distance <= (height - h).abs() {
+                radial.normalize()
+            } else if h.abs() < (height - h).abs() {
+                -a
+            } else {
+                a
+            }
+        }
+    }
+}
+
+// GLSL's sign, which unlike f32::signum gives 0 for 0
+fn glsl_sign(x: f32) -> f32 {
+    if x > 0.0 { 1.0 } else if x < 0.0 { -1.0 } else { 0.0 }
+}
+
+/// See sampleOffset in the shader
+pub fn sample_offset(sampling: Sampling, index: u32, samples: u32, frame: u32, rng: &mut u32) -> Vector2<f32> {
+    if sampling == Sampling::Halton {
+        let n = frame * samples + index + 1;
+        return Vector2::new(halton(n, 2) - 0.5, halton(n, 3) - 0.5);
+    }
+    let mut columns = 1;
+    while columns * columns < samples {
+        columns += 1;
+    }
+    let rows = (samples + columns - 1) / columns;
+    let cell = Vector2::new((index % columns) as f32, (index / columns) as f32);
+    let mut position = Vector2::new(0.5, 0.5);
+    if sampling == Sampling::Stratified {
+        let x = random_float(rng);
+        position = Vector2::new(x, random_float(rng));
+    }
+    (cell + position).div_element_wise(Vector2::new(columns as f32, rows as f32)) - Vector2::new(0.5, 0.5)
+}
+
+/// See halton in the shader
+pub fn halton(mut index: u32, base: u32) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+pub fn pcg_hash(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+pub fn random_float(state: &mut u32) -> f32 {
+    *state = pcg_hash(*state);
+    (*state >> 8) as f32 / 16777216.0
+}
+
+/// See sampleDisk in the shader
+pub fn sample_disk(rng: &mut u32) -> Vector2<f32> {
+    let r = random_float(rng).sqrt();
+    let phi = 2.0 * PI * random_float(rng);
+    r * Vector2::new(phi.cos(), phi.sin())
+}
+
+pub fn cosine_sample_hemisphere(n: Vector3<f32>, rng: &mut u32) -> Vector3<f32> {
+    let phi = 2.0 * PI * random_float(rng);
+    let r2 = random_float(rng);
+    let r = r2.sqrt();
+
+    let axis = if n.x.abs() > 0.5 { Vector3::unit_y() } else { Vector3::unit_x() };
+    let tangent = axis.cross(n).normalize();
+    let bitangent = n.cross(tangent);
+    r * phi.cos() * tangent + r * phi.sin() * bitangent + (1.0 - r2).sqrt() * n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden_tests::load_scene;
+    use crate::scene::DEFAULT_SCENE;
+
+    // Closest hit distance over every primitive in the scene, without the tree
+    fn brute_force_distance(tracer: &CpuTracer, p: Vector3<f32>, d: Vector3<f32>, t_min: f32, t_max: f32) -> Option<f32> {
+        let mut distances = Vec::new();
+        for plane in tracer.planes {
+            distances.push(intersect_ray_plane(p, d, plane.point, plane.normal));
+        }
+        for sphere in tracer.spheres {
+            let (t1, t2) = intersect_ray_sphere(p, d, sphere.center.truncate(), sphere.radius);
+            distances.extend(&[t1, t2]);
+        }
+        for shape in tracer.shapes {
+            let (t1, t2) = intersect_ray_shape(p, d, shape);
+            distances.extend(&[t1, t2]);
+        }
+        for &(mesh, [a, b, c]) in &tracer.triangles {
+            let (t, _, _) = intersect_ray_triangle(
+                p, d,
+                mesh.positions[a as usize],
+                mesh.positions[b as usize],
+                mesh.positions[c as usize],
+            );
+            distances.push(t);
+        }
+        distances.into_iter()
+            .filter(|&t| t_min <= t && t <= t_max && t < MAX_FLOAT)
+            .fold(None, |closest: Option<f32>, t| Some(closest.map_or(t, |c| c.min(t))))
+    }
+
+    #[test]
+    fn halton_known_values() {
+        let expected = [
+            (1, 2, 0.5), (2, 2, 0.25), (3, 2, 0.75), (4, 2, 0.125), (5, 2, 0.625),
+            (1, 3, 1.0 / 3.0), (2, 3, 2.0 / 3.0), (3, 3, 1.0 / 9.0), (4, 3, 4.0 / 9.0),
+        ];
+        for &(index, base, value) in &expected {
+            assert!((halton(index, base) - value).abs() < 1e-6, "halton({}, {}) is {}, expected {}",
+                index, base, halton(index, base), value);
+        }
+        assert_eq!(halton(0, 2), 0.0);
+    }
+
+    #[test]
+    fn sample_offsets_stay_in_the_pixel() {
+        let mut rng = 1;
+        for &sampling in &[Sampling::Grid, Sampling::Stratified, Sampling::Halton] {
+            for samples in 1..=10 {
+                for frame in 0..20 {
+                    for index in 0..samples {
+                        // Offsets are from the pixel's center, so this is where in the pixel the
+                        // sample lands
+                        let position = sample_offset(sampling, index, samples, frame, &mut rng) + Vector2::new(0.5, 0.5);
+                        assert!(
+                            (0.0..1.0).contains(&position.x) && (0.0..1.0).contains(&position.y),
+                            "{:?} sample {} of {} in frame {} lands outside the pixel at {:?}",
+                            sampling, index, samples, frame, position,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stereo_views_split_the_image() {
+        for &(width, height) in &[(8, 6), (9, 7)] {
+            assert_eq!(stereo_view(Stereo::Mono, width - 1, height - 1, width, height).0, 0.0);
+            for &stereo in &[Stereo::SideBySide, Stereo::TopBottom] {
+                let mut pixels = [0, 0];
+                for y in 0..height {
+                    for x in 0..width {
+                        let (eye, pixel, size) = stereo_view(stereo, x, y, width, height);
+                        let along = if stereo == Stereo::SideBySide { x } else { y };
+                        let length = if stereo == Stereo::SideBySide { width } else { height };
+                        // Left eye first, and the two views cover the image between them
+                        assert_eq!(eye, if along < length / 2 { -1.0 } else { 1.0 }, "{:?} {}x{} at {}, {}", stereo, width, height, x, y);
+                        assert!(
+                            (0.0..size.x).contains(&pixel.x) && (0.0..size.y).contains(&pixel.y),
+                            "{:?} {}x{} puts {}, {} at {:?} in a {:?} view", stereo, width, height, x, y, pixel, size,
+                        );
+                        pixels[(eye > 0.0) as usize] += 1;
+                        if x == width - 1 && y == height - 1 {
+                            assert_eq!(size.x * size.y, pixels[1] as f32);
+                        }
+                    }
+                }
+                assert_eq!(pixels[0] + pixels[1], width * height);
+            }
+        }
+    }
+
+    #[test]
+    fn eyes_meet_at_convergence() {
+        let mut camera = Camera::from_origin();
+        camera.eye_separation = 0.5;
+        camera.convergence = 3.0;
+        let view_size = Vector2::new(64.0, 48.0);
+        for &projection in &[Projection::Perspective, Projection::Fisheye, Projection::Equirectangular] {
+            camera.projection = projection;
+            for &pixel in &[Vector2::new(32.0, 24.0), Vector2::new(5.5, 40.5), Vector2::new(60.0, 3.0)] {
+                let (o, d) = canvas_to_viewport(&camera, pixel, view_size);
+                let (left_o, left_d) = eye_ray(&camera, -1.0, o, d);
+                let (right_o, right_d) = eye_ray(&camera, 1.0, o, d);
+                assert!((left_o - right_o).magnitude() > 0.4, "{:?} eyes at {:?} and {:?}", projection, left_o, right_o);
+                // Perspective rays are a unit deep, the others a unit long, so t is the depth
+                // along them
+                let t = camera.convergence;
+                let (left, right) = (left_o + left_d * t, right_o + right_d * t);
+                assert!((left - right).magnitude() < 1e-5, "{:?} eyes at {:?} meet at {:?} and {:?}", projection, pixel, left, right);
+                assert!((left - (o + d * t)).magnitude() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn bvh_matches_brute_force() {
+        const SCENE_EXTENT: f32 = 50.0;
+
+        let scenes = ["sphere_grid", "small_spheres", "mesh", "shapes"].iter()
+            .map(|&name| (name, load_scene(name)))
+            .chain(Some(("demo", DEFAULT_SCENE.parse().unwrap())));
+        for (name, scene) in scenes {
+            let tracer = CpuTracer::new(&scene);
+            let nodes = &tracer.bvh.nodes;
+            let mut rng = 1;
+            // Somewhere in or a bit around a random node, so the rays cover small primitives next to
+            // big ones as well as rays that start outside the tree or miss it. Kept near the middle of
+            // the scene, far away from it f32 can't agree on where a ray grazes the huge ground sphere
+            let random_point = |rng: &mut u32| {
+                let bounds = nodes[(random_float(rng) * nodes.len() as f32) as usize].bounds;
+                let along = Vector3::new(random_float(rng), random_float(rng), random_float(rng)) * 2.0
+                    - Vector3::new(0.5, 0.5, 0.5);
+                let point = bounds.min + (bounds.max - bounds.min).mul_element_wise(along);
+                point.map(|c| c.clamp(-SCENE_EXTENT, SCENE_EXTENT))
+            };
+
+            for _ in 0..2000 {
+                let p = random_point(&mut rng);
+                let d = (random_point(&mut rng) - p).normalize();
+                let expected = brute_force_distance(&tracer, p, d, 0.001, MAX_FLOAT);
+                let actual = tracer.closest_intersection(p, d, 0.001, MAX_FLOAT).map(|(_, t)| t);
+                match (expected, actual) {
+                    (None, None) => {}
+                    (Some(expected), Some(actual)) => assert!(
+                        (expected - actual).abs() <= 1e-4 * expected.max(1.0),
+                        "{}: ray from {:?} along {:?} hit at {} instead of {}", name, p, d, actual, expected,
+                    ),
+                    _ => panic!("{}: ray from {:?} along {:?} hit at {:?} instead of {:?}", name, p, d, actual, expected),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scenes_without_bvh_primitives() {
+        let scenes = [
+            ("planes only", r#"Scene(
+                planes: [(point: (0.0, -1.0, 0.0), normal: (0.0, 1.0, 0.0), material: (color: (1.0, 1.0, 0.0)))],
+                lights: [Ambient(intensity: 1.0)],
+            )"#),
+            ("empty", "Scene()"),
+        ];
+        for &(name, source) in &scenes {
+            let scene: Scene = source.parse().unwrap_or_else(|e| panic!("{}: {}", name, e));
+            let tracer = CpuTracer::new(&scene);
+            assert!(tracer.bvh.primitives.is_empty(), "{}", name);
+
+            let down = Vector3::new(0.0, -1.0, 1.0).normalize();
+            let hit = tracer.closest_intersection(Vector3::new(0.0, 0.0, 0.0), down, 0.001, MAX_FLOAT);
+            assert_eq!(hit.map(|(_, t)| t), brute_force_distance(&tracer, Vector3::new(0.0, 0.0, 0.0), down, 0.001, MAX_FLOAT), "{}", name);
+            assert_eq!(hit.is_some(), !scene.planes.is_empty(), "{}", name);
+            tracer.render(&scene.camera, 8, 6, 1);
+            tracer.render_path_traced(&scene.camera, 8, 6, 1);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{IMAGE_HEIGHT, IMAGE_WIDTH};
+use crate::scene::RenderSettings;
 
 use vulkano::device::{Device, DeviceExtensions, Queue};
 use vulkano::instance::{PhysicalDevice, Instance};
@@ -34,7 +34,7 @@ pub struct Engine {
 }
 
 impl Engine {
-    pub fn new(event_loop: &EventLoop<()>) -> Self {
+    pub fn new(event_loop: &EventLoop<()>, settings: &RenderSettings) -> Self {
         // Create Vulkano instance
         // Get required extensions to draw window
         let required_extensions = vulkano_win::required_extensions();
@@ -51,18 +51,13 @@ impl Engine {
         surface.window()
             // LogicalSize takes a type P that implements dpi::Pixel, which does not have
             // a usize implementation. Easy enough to cast to u32
-            .set_inner_size(LogicalSize::new(IMAGE_WIDTH as u32, IMAGE_HEIGHT as u32));
+            .set_inner_size(LogicalSize::new(settings.width, settings.height));
         match surface.window().set_cursor_grab(true) {
             Ok(_) => println!("Got cursor lock on window."),
             Err(_) => panic!("Couldn't get cursor lock on window!"),
         }
         surface.window().set_cursor_visible(false);
 
-        // Set default position for mouse
-        let default_mouse_position = PhysicalPosition {
-            x: IMAGE_WIDTH as i32 / 2,
-            y: IMAGE_HEIGHT as i32 / 2,
-        };
 
         // Grab the first available physical device
         let physical = PhysicalDevice::enumerate(&instance).next().expect("no device available");
@@ -92,6 +87,9 @@ impl Engine {
 
         // Create the swapchain
         let dimensions: [u32; 2] = surface.window().inner_size().into();
+
+        // Set default position for mouse
+        let default_mouse_position = Engine::window_center(dimensions);
         let (swapchain, images) = {
             // Query surface capabilities
             let caps = surface.capabilities(physical).unwrap();
@@ -103,6 +101,18 @@ impl Engine {
             // Choosing the internal format the images will have. Just take the first
             let format = caps.supported_formats[0].0;
 
+            // Fifo is always supported and waits for vblank. Without vsync, take whichever
+            // non-blocking mode the surface offers
+            let present_mode = if settings.vsync {
+                PresentMode::Fifo
+            } else if caps.present_modes.mailbox {
+                PresentMode::Mailbox
+            } else if caps.present_modes.immediate {
+                PresentMode::Immediate
+            } else {
+                PresentMode::Fifo
+            };
+
             Swapchain::new(
                 device.clone(),
                 surface.clone(),
@@ -114,7 +124,7 @@ impl Engine {
                 &queue,
                 SurfaceTransform::Identity,
                 alpha,
-                PresentMode::Fifo,
+                present_mode,
                 FullscreenExclusive::Default,
                 true,
                 ColorSpace::SrgbNonLinear,
@@ -237,8 +247,16 @@ impl Engine {
             );
             self.framebuffers = new_framebuffers;
             self.images = new_images;
+            self.default_mouse_position = Engine::window_center(dimensions);
             self.recreate_swapchain = false;
         }
     }
+
+    fn window_center(dimensions: [u32; 2]) -> PhysicalPosition<i32> {
+        PhysicalPosition {
+            x: dimensions[0] as i32 / 2,
+            y: dimensions[1] as i32 / 2,
+        }
+    }
 }
 
@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::codecs::hdr::HdrDecoder;
+use image::{ImageBuffer, Rgb};
+
+use crate::cs;
+use crate::object_traits::Uniform;
+
+// Kinds of background, matching the BACKGROUND_ constants in the shader
+const BACKGROUND_SOLID: i32 = 0;
+const BACKGROUND_GRADIENT: i32 = 1;
+const BACKGROUND_IMAGE: i32 = 2;
+
+/// Floating point RGB image, so skies can be brighter than white
+pub type HdrImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+/// What rays that miss everything see
+#[derive(Debug, Clone)]
+pub enum Background {
+    Solid([f32; 3]),
+    /// Blends from `bottom` straight down to `top` straight up
+    Gradient { top: [f32; 3], bottom: [f32; 3] },
+    /// Equirectangular image, longitude across and latitude down, scaled by `intensity`
+    Image { image: HdrImage, intensity: f32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Environment {
+    pub(crate) background: Background,
+    /// Whether reflected, refracted and bounced rays see the background too, lighting the scene
+    /// with it. Without this only rays straight from the camera do
+    pub(crate) lighting: bool,
+}
+
+impl Environment {
+    pub fn new(background: Background, lighting: bool) -> Self {
+        Environment { background, lighting }
+    }
+
+    /// The image of an `Image` background
+    pub fn image(&self) -> Option<&HdrImage> {
+        match &self.background {
+            Background::Image { image, .. } => Some(image),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Environment {
+    /// Black, like empty space
+    fn default() -> Self {
+        Environment::new(Background::Solid([0.0; 3]), false)
+    }
+}
+
+/// Loads a Radiance .hdr file
+pub fn load_hdr(path: &Path) -> Result<HdrImage, image::ImageError> {
+    let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?;
+    let data = pixels.iter().flat_map(|pixel| pixel.0.iter().copied()).collect();
+    Ok(HdrImage::from_raw(metadata.width, metadata.height, data).expect("decoder returned too few pixels"))
+}
+
+impl Uniform for Environment {
+    type Uniform = cs::ty::Environment;
+
+    fn to_uniform(&self) -> Self::Uniform {
+        let (kind, top, bottom, intensity) = match self.background {
+            Background::Solid(color) => (BACKGROUND_SOLID, color, color, 1.0),
+            Background::Gradient { top, bottom } => (BACKGROUND_GRADIENT, top, bottom, 1.0),
+            Background::Image { intensity, .. } => (BACKGROUND_IMAGE, [0.0; 3], [0.0; 3], intensity),
+        };
+        cs::ty::Environment {
+            top: [top[0], top[1], top[2], 0.0],
+            bottom: [bottom[0], bottom[1], bottom[2], 0.0],
+            kind,
+            lighting: self.lighting as i32,
+            intensity,
+            padding: 0.0,
+        }
+    }
+}
@@ -0,0 +1,282 @@
+// Golden image regression tests. Each scene is traced by `CpuTracer` and compared against a
+// reference PNG checked in under tests/golden. When a test fails the actual render and a diff
+// image, with every pixel that changed painted red, are written to target/golden.
+//
+// After an intentional change to the lighting model, regenerate the references with
+//     UPDATE_GOLDEN=1 cargo test golden
+// and look over the new images before committing them.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use image::RgbImage;
+
+use crate::cpu_tracer::CpuTracer;
+use crate::headless;
+use crate::scene::{RenderMode, Scene, DEFAULT_SCENE};
+
+// Small enough to keep the references tiny, big enough to still see what went wrong
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 90;
+
+// Path traced and accumulating scenes are averaged over this many frames. Still noisy, but the
+// noise is deterministic
+const ACCUMULATED_FRAMES: u32 = 16;
+
+// Largest difference in any channel, out of 255, before a pixel counts as changed. Leaves room for
+// libm differences between platforms
+const TOLERANCE: u8 = 2;
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn output_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/golden")
+}
+
+/// One of the scene files next to the golden images, by name without the extension. Shared with
+/// the unit tests elsewhere
+pub(crate) fn load_scene(name: &str) -> Scene {
+    let path = golden_dir().join(format!("{}.ron", name));
+    Scene::load(&path).unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn check_golden(name: &str, scene: &Scene) {
+    let tracer = CpuTracer::new(scene);
+    let actual = match scene.render.mode {
+        RenderMode::Classic => tracer.render(&scene.camera, WIDTH as usize, HEIGHT as usize, ACCUMULATED_FRAMES),
+        RenderMode::PathTraced =>
+            tracer.render_path_traced(&scene.camera, WIDTH as usize, HEIGHT as usize, ACCUMULATED_FRAMES),
+    };
+    let actual = RgbImage::from_raw(WIDTH, HEIGHT, actual).unwrap();
+
+    let reference_path = golden_dir().join(format!("{}.png", name));
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        actual.save(&reference_path).unwrap();
+        return;
+    }
+
+    let expected = image::open(&reference_path)
+        .unwrap_or_else(|e| panic!(
+            "couldn't open {}: {}. Run with UPDATE_GOLDEN=1 to create it",
+            reference_path.display(), e,
+        ))
+        .to_rgb8();
+    assert_eq!(expected.dimensions(), actual.dimensions(), "{} has the wrong size", reference_path.display());
+
+    let (diff, changed) = diff_images(&expected, &actual, TOLERANCE);
+    if changed > 0 {
+        let output = output_dir();
+        fs::create_dir_all(&output).unwrap();
+        let actual_path = output.join(format!("{}.actual.png", name));
+        let diff_path = output.join(format!("{}.diff.png", name));
+        actual.save(&actual_path).unwrap();
+        diff.save(&diff_path).unwrap();
+        panic!(
+            "{} pixels differ from {}, see {} and {}",
+            changed, reference_path.display(), actual_path.display(), diff_path.display(),
+        );
+    }
+}
+
+// Returns the expected image dimmed to grey with every pixel that's off by more than `tolerance`
+// painted red, along with how many such pixels there are
+fn diff_images(expected: &RgbImage, actual: &RgbImage, tolerance: u8) -> (RgbImage, usize) {
+    let mut changed = 0;
+    let diff = RgbImage::from_fn(expected.width(), expected.height(), |x, y| {
+        let e = expected.get_pixel(x, y);
+        let a = actual.get_pixel(x, y);
+        let off = e.0.iter().zip(a.0.iter()).any(|(e, a)| (*e as i16 - *a as i16).abs() > tolerance as i16);
+        if off {
+            changed += 1;
+            image::Rgb([255, 0, 0])
+        } else {
+            let grey = ((e[0] as u16 + e[1] as u16 + e[2] as u16) / 9) as u8;
+            image::Rgb([grey, grey, grey])
+        }
+    });
+    (diff, changed)
+}
+
+#[test]
+fn golden_demo() {
+    check_golden("demo", &DEFAULT_SCENE.parse().unwrap());
+}
+
+#[test]
+fn golden_shadows() {
+    check_golden("shadows", &load_scene("shadows"));
+}
+
+#[test]
+fn golden_reflections() {
+    check_golden("reflections", &load_scene("reflections"));
+}
+
+#[test]
+fn golden_ambient_light() {
+    check_golden("ambient_light", &load_scene("ambient_light"));
+}
+
+#[test]
+fn golden_point_light() {
+    check_golden("point_light", &load_scene("point_light"));
+}
+
+#[test]
+fn golden_directional_light() {
+    check_golden("directional_light", &load_scene("directional_light"));
+}
+
+#[test]
+fn golden_mesh() {
+    check_golden("mesh", &load_scene("mesh"));
+}
+
+#[test]
+fn golden_sphere_grid() {
+    check_golden("sphere_grid", &load_scene("sphere_grid"));
+}
+
+#[test]
+fn golden_small_spheres() {
+    check_golden("small_spheres", &load_scene("small_spheres"));
+}
+
+#[test]
+fn golden_shapes() {
+    check_golden("shapes", &load_scene("shapes"));
+}
+
+#[test]
+fn golden_glass() {
+    check_golden("glass", &load_scene("glass"));
+}
+
+#[test]
+fn golden_path_traced() {
+    check_golden("path_traced", &load_scene("path_traced"));
+}
+
+#[test]
+fn golden_path_traced_sky() {
+    check_golden("path_traced_sky", &load_scene("path_traced_sky"));
+}
+
+#[test]
+fn golden_area_light() {
+    check_golden("area_light", &load_scene("area_light"));
+}
+
+#[test]
+fn golden_emissive() {
+    check_golden("emissive", &load_scene("emissive"));
+}
+
+#[test]
+fn golden_colored_lights() {
+    check_golden("colored_lights", &load_scene("colored_lights"));
+}
+
+#[test]
+fn golden_spot_light() {
+    check_golden("spot_light", &load_scene("spot_light"));
+}
+
+#[test]
+fn golden_attenuation() {
+    check_golden("attenuation", &load_scene("attenuation"));
+}
+
+#[test]
+fn golden_textures() {
+    check_golden("textures", &load_scene("textures"));
+}
+
+#[test]
+fn golden_normal_maps() {
+    check_golden("normal_maps", &load_scene("normal_maps"));
+}
+
+#[test]
+fn golden_gradient_sky() {
+    check_golden("gradient_sky", &load_scene("gradient_sky"));
+}
+
+#[test]
+fn golden_hdr_environment() {
+    check_golden("hdr_environment", &load_scene("hdr_environment"));
+}
+
+#[test]
+fn golden_supersampling() {
+    check_golden("supersampling", &load_scene("supersampling"));
+}
+
+#[test]
+fn golden_halton_accumulation() {
+    check_golden("halton_accumulation", &load_scene("halton_accumulation"));
+}
+
+#[test]
+fn golden_camera_fov() {
+    check_golden("camera_fov", &load_scene("camera_fov"));
+}
+
+#[test]
+fn golden_depth_of_field() {
+    check_golden("depth_of_field", &load_scene("depth_of_field"));
+}
+
+#[test]
+fn golden_orthographic() {
+    check_golden("orthographic", &load_scene("orthographic"));
+}
+
+#[test]
+fn golden_fisheye() {
+    check_golden("fisheye", &load_scene("fisheye"));
+}
+
+#[test]
+fn golden_equirectangular() {
+    check_golden("equirectangular", &load_scene("equirectangular"));
+}
+
+#[test]
+fn golden_stereo_side_by_side() {
+    check_golden("stereo_side_by_side", &load_scene("stereo_side_by_side"));
+}
+
+#[test]
+fn golden_stereo_panorama() {
+    check_golden("stereo_panorama", &load_scene("stereo_panorama"));
+}
+
+// Checks the shader against its CPU port. Needs a Vulkan device, so it only runs on request with
+//     cargo test -- --ignored
+// GPUs don't round sqrt and pow the same way the CPU does, which flips the odd pixel along edges,
+// hence the looser limits
+#[test]
+#[ignore]
+fn gpu_matches_cpu_tracer() {
+    let scene: Scene = DEFAULT_SCENE.parse().unwrap();
+    // Deliberately not a multiple of the 8x8 work group size
+    let (width, height) = (636, 357);
+
+    let expected = CpuTracer::new(&scene)
+        .render(&scene.camera, width as usize, height as usize, 1);
+    let expected = RgbImage::from_raw(width, height, expected).unwrap();
+    let actual = RgbImage::from_raw(width, height, headless::render_gpu(&scene, width, height, 1)).unwrap();
+
+    let (diff, changed) = diff_images(&expected, &actual, 8);
+    if changed > (width * height) as usize / 1000 {
+        let output = output_dir();
+        fs::create_dir_all(&output).unwrap();
+        actual.save(output.join("gpu.actual.png")).unwrap();
+        diff.save(output.join("gpu.diff.png")).unwrap();
+        panic!("{} pixels differ between the GPU and CPU renders, see {}", changed, output.display());
+    }
+}
@@ -0,0 +1,142 @@
+use crate::cpu_tracer::CpuTracer;
+use crate::renderer::Renderer;
+use crate::scene::{RenderMode, Scene};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer};
+use vulkano::device::{Device, DeviceExtensions};
+use vulkano::format::Format;
+use vulkano::image::{Dimensions, ImageUsage, StorageImage};
+use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
+use vulkano::sync::GpuFuture;
+
+/// Renders a single frame of `scene` without a window and writes it to `output`. The file format
+/// is picked from the extension, either `.png` or `.ppm`. With `cpu` set the frame is traced by
+/// `CpuTracer` and Vulkan isn't touched at all. Path traced scenes, and classic ones that
+/// accumulate, are averaged over `frames` frames, the same as holding the camera still for that
+/// long in the window
+pub fn render_to_file(scene: &Scene, output: &Path, cpu: bool, frames: u32) -> io::Result<()> {
+    // Check the output format up front so we don't render a frame only to throw it away
+    let format = OutputFormat::from_path(output)?;
+
+    let (width, height) = (scene.render.width, scene.render.height);
+    let pixels = match (cpu, scene.render.mode) {
+        (true, RenderMode::Classic) =>
+            CpuTracer::new(scene).render(&scene.camera, width as usize, height as usize, frames),
+        (true, RenderMode::PathTraced) =>
+            CpuTracer::new(scene).render_path_traced(&scene.camera, width as usize, height as usize, frames),
+        (false, _) => render_gpu(scene, width, height, frames),
+    };
+    format.write(output, width, height, &pixels)
+}
+
+// Renders on the first available device and returns 8 bit RGB, same as `CpuTracer::render`.
+// `frames` only matters for scenes that accumulate, other frames come out the same every time
+pub(crate) fn render_gpu(scene: &Scene, width: u32, height: u32, frames: u32) -> Vec<u8> {
+    // No window, so no surface extensions needed
+    let instance = Instance::new(None, &InstanceExtensions::none(), None)
+        .expect("failed to create instance");
+
+    // Grab the first available physical device
+    let physical = PhysicalDevice::enumerate(&instance).next().expect("no device available");
+
+    // Find an appropriate queue for this work
+    let queue_family = physical.queue_families()
+        .find(|&q| q.supports_compute())
+        .expect("couldn't find a compute queue family");
+
+    let device_ext = DeviceExtensions {
+        khr_storage_buffer_storage_class: true,
+        ..DeviceExtensions::none()
+    };
+
+    let (device, mut queues) = Device::new(
+        physical,
+        physical.supported_features(),
+        &device_ext,
+        [(queue_family, 0.5)].iter().cloned(),
+    ).expect("failed to create device");
+    let queue = queues.next().unwrap();
+
+    let mut renderer = Renderer::new(queue.clone(), scene);
+
+    // The shader writes into this image, which then gets copied somewhere the CPU can read it
+    let image = StorageImage::with_usage(
+        device.clone(),
+        Dimensions::Dim2d { width, height },
+        Format::R8G8B8A8Unorm,
+        ImageUsage {
+            storage: true,
+            transfer_source: true,
+            ..ImageUsage::none()
+        },
+        Some(queue.family()),
+    ).expect("failed to create image");
+
+    let pixels = CpuAccessibleBuffer::from_iter(
+        device.clone(),
+        BufferUsage::all(),
+        false,
+        (0..width * height * 4).map(|_| 0u8),
+    ).expect("failed to create readback buffer");
+
+    let mut command_buffer = AutoCommandBufferBuilder::primary_one_time_submit(device, queue.family())
+        .unwrap();
+    let frames = if scene.render.accumulates(scene.render.mode) { frames } else { 1 };
+    for _ in 0..frames {
+        renderer.dispatch(&mut command_buffer, image.clone(), &scene.camera);
+    }
+    command_buffer.copy_image_to_buffer(image, pixels.clone()).unwrap();
+    let command_buffer = command_buffer.build().unwrap();
+
+    command_buffer.execute(queue)
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    // Drop the alpha channel, the shader always writes 1.0 there anyway
+    let pixels = pixels.read().unwrap();
+    pixels.chunks_exact(4)
+        .flat_map(|pixel| pixel[..3].iter().copied())
+        .collect()
+}
+
+enum OutputFormat {
+    Png,
+    Ppm,
+}
+
+impl OutputFormat {
+    fn from_path(path: &Path) -> io::Result<Self> {
+        let extension = path.extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("png") => Ok(OutputFormat::Png),
+            Some("ppm") => Ok(OutputFormat::Ppm),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported output format for {}, expected .png or .ppm", path.display()),
+            )),
+        }
+    }
+
+    /// `rgb` is tightly packed 8 bit RGB, row by row from the top
+    fn write(&self, path: &Path, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+        match self {
+            OutputFormat::Png => image::save_buffer(path, rgb, width, height, image::ColorType::Rgb8)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+            OutputFormat::Ppm => {
+                let mut file = BufWriter::new(File::create(path)?);
+                write!(file, "P6\n{} {}\n255\n", width, height)?;
+                file.write_all(rgb)?;
+                file.flush()
+            }
+        }
+    }
+}
@@ -1,30 +1,132 @@
-use cgmath::{Vector3, Vector4};
+use cgmath::{Angle, Deg, Vector3, Vector4};
 
 use crate::cs;
 use crate::object_traits::Uniform;
 
+// Shadow rays per shaded point for area lights that don't say otherwise
+pub const DEFAULT_AREA_SAMPLES: u32 = 16;
+
 #[derive(Copy, Clone)]
 pub enum LightType {
     Ambient = 0,
     Point = 1,
-    Directional = 2
+    Directional = 2,
+    /// Light given off evenly by the surface of a sphere
+    Sphere = 3,
+    /// Light given off evenly by a rectangle, from both sides
+    Rectangle = 4,
+    /// Point light shining in a cone
+    Spot = 5,
 }
 
 #[repr(C)]
 pub struct Light {
-    light_type: LightType,
-    intensity: f32,
-    position: Vector4<f32> // Will be used for direction in case it's a directional light
+    pub(crate) light_type: LightType,
+    /// RGB, scaled by `intensity`
+    pub(crate) color: [f32; 3],
+    /// Constant, linear and quadratic terms of how lights with a position fade with distance.
+    /// They get divided by `constant + linear * d + quadratic * d * d`
+    pub(crate) attenuation: [f32; 3],
+    /// Distance past which the light reaches nothing at all, 0 for no limit
+    pub(crate) range: f32,
+    pub(crate) intensity: f32,
+    pub(crate) position: Vector4<f32>,
+    // Which way directional and spot lights shine
+    pub(crate) direction: Vector4<f32>,
+    // Edges of a rectangle light, centered on position
+    pub(crate) u: Vector4<f32>,
+    pub(crate) v: Vector4<f32>,
+    pub(crate) radius: f32,
+    // Shadow rays a sphere or rectangle light gets split into in classic mode. More of them make
+    // for smoother soft shadows
+    pub(crate) samples: u32,
+    // Cosines of the angles from a spot light's direction where it starts to fade out, and where
+    // it's gone
+    pub(crate) inner_cos: f32,
+    pub(crate) outer_cos: f32,
 }
 
 impl Light {
+    /// A white light. `position` is the direction towards the light for directional lights.
+    /// Sphere and rectangle lights start out as a single point, give them a size with
+    /// `with_radius` or `with_edges`. Spot lights shine every way until given `with_direction`
+    /// and `with_cone`
     pub fn new(light_type: LightType, intensity: f32, position: Option<Vector3<f32>>) -> Self {
         let position = match position {
             None => Vector4::new(0.0, 0.0, 0.0, 0.0),
             Some(position) => Vector4::new(position.x, position.y, position.z, 0.0)
         };
+        let (position, direction) = match light_type {
+            LightType::Directional => (Vector4::new(0.0, 0.0, 0.0, 0.0), -position),
+            _ => (position, Vector4::new(0.0, 0.0, 0.0, 0.0)),
+        };
+
+        Self {
+            light_type,
+            color: [1.0; 3],
+            attenuation: [1.0, 0.0, 0.0],
+            range: 0.0,
+            intensity,
+            position,
+            direction,
+            u: Vector4::new(0.0, 0.0, 0.0, 0.0),
+            v: Vector4::new(0.0, 0.0, 0.0, 0.0),
+            radius: 0.0,
+            samples: DEFAULT_AREA_SAMPLES,
+            inner_cos: -1.0,
+            outer_cos: -1.0,
+        }
+    }
+
+    /// Color of the light, multiplied by its intensity
+    pub fn with_color(mut self, color: [f32; 3]) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Terms of what the light's intensity gets divided by at distance d,
+    /// `constant + linear * d + quadratic * d * d`. Defaults to 1, 0, 0, no falloff at all
+    pub fn with_attenuation(mut self, constant: f32, linear: f32, quadratic: f32) -> Self {
+        self.attenuation = [constant, linear, quadratic];
+        self
+    }
+
+    /// Distance past which the light reaches nothing, 0 for no limit
+    pub fn with_range(mut self, range: f32) -> Self {
+        self.range = range;
+        self
+    }
+
+    /// Radius of a sphere light
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    /// Edges of a rectangle light
+    pub fn with_edges(mut self, u: Vector3<f32>, v: Vector3<f32>) -> Self {
+        self.u = u.extend(0.0);
+        self.v = v.extend(0.0);
+        self
+    }
+
+    /// Which way a spot light shines
+    pub fn with_direction(mut self, direction: Vector3<f32>) -> Self {
+        self.direction = direction.extend(0.0);
+        self
+    }
+
+    /// Angles from a spot light's direction where it starts to fade out, and where it's gone
+    pub fn with_cone(mut self, inner: Deg<f32>, outer: Deg<f32>) -> Self {
+        self.inner_cos = inner.cos();
+        self.outer_cos = outer.cos();
+        self
+    }
 
-        Self { light_type, intensity, position }
+    /// How many shadow rays a sphere or rectangle light gets split into
+    pub fn with_samples(mut self, samples: u32) -> Self {
+        self.samples = samples;
+        self
     }
 }
 
@@ -34,8 +136,17 @@ impl Uniform for Light {
     fn to_uniform(&self) -> Self::Uniform {
         cs::ty::Light {
             position: self.position.into(),
-            intensity: self.intensity.into(),
-            lightType: (self.light_type as i32).into(),
+            direction: self.direction.into(),
+            u: self.u.into(),
+            v: self.v.into(),
+            color: [self.color[0], self.color[1], self.color[2], 0.0],
+            attenuation: [self.attenuation[0], self.attenuation[1], self.attenuation[2], self.range],
+            intensity: self.intensity,
+            lightType: self.light_type as i32,
+            radius: self.radius,
+            samples: self.samples as i32,
+            innerCos: self.inner_cos,
+            outerCos: self.outer_cos,
             padding: [0.0; 2],
         }
     }
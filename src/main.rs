@@ -2,121 +2,103 @@ mod sphere;
 mod camera;
 mod engine;
 mod light;
+mod material;
+mod mesh;
+mod obj;
 mod object_traits;
+mod scene;
+mod cli;
+mod renderer;
+mod headless;
+mod cpu_tracer;
+mod bvh;
+mod shape;
+mod texture;
+mod environment;
+#[cfg(test)]
+mod golden_tests;
 
-use crate::sphere::Sphere;
-use crate::object_traits::Uniform;
-use std::sync::Arc;
-use vulkano::buffer::{BufferUsage, CpuBufferPool};
 use vulkano::command_buffer::AutoCommandBufferBuilder;
 use vulkano::sync::{GpuFuture, FlushError};
-use vulkano::pipeline::ComputePipeline;
-use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
-use vulkano::descriptor::PipelineLayoutAbstract;
-use crate::camera::Camera;
+use crate::cpu_tracer::CpuTracer;
 use crate::engine::Engine;
-use crate::light::{Light, LightType};
+use crate::renderer::Renderer;
+use crate::scene::{Scene, DEFAULT_SCENE};
+use crate::cli::{Args, USAGE};
 use cgmath::{Vector3, InnerSpace, Rad, Angle};
+use std::process;
 use std::time::Instant;
 use device_query::{Keycode, DeviceState, DeviceQuery};
 use std::f32::consts::FRAC_PI_2;
 use winit::event_loop::{EventLoop, ControlFlow};
-use winit::event::{Event, WindowEvent};
+use winit::event::{Event, MouseScrollDelta, WindowEvent};
 use vulkano::swapchain::AcquireError;
 use vulkano::swapchain;
 use vulkano::sync;
 
-const IMAGE_WIDTH: usize = 1920;
-const IMAGE_HEIGHT: usize = 1080;
+// Touchpad scrolling that counts as one step of a mouse wheel
+const PIXELS_PER_LINE: f32 = 40.0;
 
 fn main() {
-    // Create event loop for window
-    let event_loop = EventLoop::new();
-    let mut engine = Engine::new(&event_loop);
-
-    // Set up Spheres
-    let scene: [Sphere; 4] = [
-        Sphere::new(
-            0.0, -1.0, 3.0,
-            1,
-            &[1.0, 0.0, 0.0, 0.0],
-            500,
-            0.2,
-        ),
-        Sphere::new(2.0, 0.0, 4.0,
-                    1,
-                    &[0.0, 0.0, 1.0, 0.0],
-                    500,
-                    0.3,
-        ),
-        Sphere::new(-2.0, 0.0, 4.0,
-                    1,
-                    &[0.0, 1.0, 0.0, 0.0],
-                    10,
-                    0.4,
-        ),
-        Sphere::new(0.0, -5001.0, 4.0,
-                    5000,
-                    &[1.0, 1.0, 0.0, 0.0],
-                    1000,
-                    0.5,
-        ),
-    ];
-
-    // Set up Lights
-    let lights: [Light; 3] = [
-        Light::new(LightType::Ambient, 0.2, None),
-        Light::new(LightType::Point, 0.6, Some(Vector3::new(2.0, 1.0, 0.0))),
-        Light::new(LightType::Directional, 0.2, Some(Vector3::new(1.0, 4.0, 4.0))),
-    ];
-
-    let shader = cs::Shader::load(engine.device.clone()).expect("failed to create shader module");
-
-    let compute_pipeline = Arc::new(
-        ComputePipeline::new(engine.device.clone(), &shader.main_entry_point(), &(), None)
-            .expect("failed to create compute pipeline")
-    );
-
-    // Initialize camera uniform buffer
-    let mut camera = Camera::from_origin();
-    let camera_buffer = CpuBufferPool::<cs::ty::Camera>::new(engine.device.clone(), BufferUsage::all());
-    // Initialize spheres uniform buffer
-    let spheres_buffer = CpuBufferPool::<cs::ty::Spheres>::new(engine.device.clone(), BufferUsage::all());
-
-    let spheres_buffer_subbuffer = {
-        let spheres: [cs::ty::Sphere; 4] = [
-            scene[0].to_uniform(),
-            scene[1].to_uniform(),
-            scene[2].to_uniform(),
-            scene[3].to_uniform(),
-        ];
-
-        let uniform_data = cs::ty::Spheres {
-            instances: spheres.into(),
-        };
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return;
+    }
 
-        Arc::new(spheres_buffer.next(uniform_data).unwrap())
+    // Load the scene before opening a window so bad scene files fail fast
+    let scene = match &args.scene {
+        Some(path) => Scene::load(path),
+        None => DEFAULT_SCENE.parse(),
+    };
+    let mut scene = match scene {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("Failed to load scene: {}", e);
+            process::exit(1);
+        }
     };
 
-    // Initialize lights uniform buffer
-    let lights_buffer = CpuBufferPool::<cs::ty::Lights>::new(engine.device.clone(), BufferUsage::all());
+    if let Some((width, height)) = args.resolution {
+        scene.render.width = width;
+        scene.render.height = height;
+    }
+    if let Some(samples) = args.samples {
+        scene.render.samples = samples;
+    }
 
-    let lights_buffer_subbuffer = {
-        let lights: [cs::ty::Light; 3] = [
-            lights[0].to_uniform(),
-            lights[1].to_uniform(),
-            lights[2].to_uniform(),
-        ];
+    if args.headless {
+        if let Err(e) = headless::render_to_file(&scene, &args.output, args.cpu, args.frames) {
+            eprintln!("Failed to write {}: {}", args.output.display(), e);
+            process::exit(1);
+        }
+        println!("Wrote {}", args.output.display());
+        return;
+    }
 
-        let uniform_data = cs::ty::Lights {
-            instances: lights.into(),
-        };
+    // The event loop never returns, so the scene is around for the rest of the program anyway.
+    // Leaking it lets the CPU tracer borrow it from inside the loop
+    let scene: &'static Scene = Box::leak(Box::new(scene));
+    // Only casts the odd ray, like the one to focus on
+    let tracer = CpuTracer::new(scene);
 
-        Arc::new(lights_buffer.next(uniform_data).unwrap())
-    };
+    // Create event loop for window
+    let event_loop = EventLoop::new();
+    let mut engine = Engine::new(&event_loop, &scene.render);
+
+    let mut renderer = Renderer::new(engine.queue.clone(), scene);
+    let mut camera = scene.camera.clone();
 
     // Set up input handlers
     let device_state = DeviceState::new();
+    // Keys held down during the last event, so toggles only fire once per press
+    let mut previous_keys: Vec<Keycode> = Vec::new();
     let mut window_is_focused = true; // Assume focused at startup
 
     // Set up delta_time timer
@@ -131,6 +113,46 @@ fn main() {
 
         // Handle keyboard input
         let keys: Vec<Keycode> = device_state.get_keys();
+        // P switches between classic ray tracing and path tracing
+        if keys.contains(&Keycode::P) && !previous_keys.contains(&Keycode::P) {
+            renderer.set_mode(renderer.mode().toggled());
+        }
+        // C cycles through the camera's projections
+        if keys.contains(&Keycode::C) && !previous_keys.contains(&Keycode::C) {
+            camera.projection = camera.projection.next();
+        }
+        // F focuses on whatever is in the middle of the view. Casting that one ray on the CPU
+        // beats reading it back from the GPU
+        if keys.contains(&Keycode::F) && !previous_keys.contains(&Keycode::F) {
+            if let Some(depth) = tracer.focus_depth(&camera) {
+                camera.focus_distance = depth;
+            }
+        }
+        // R reloads the materials from the scene file, so they can be tweaked without starting over
+        if keys.contains(&Keycode::R) && !previous_keys.contains(&Keycode::R) {
+            if let Some(path) = &args.scene {
+                // The frame still in flight reads the materials, wait for it to let go of them
+                if let Some(previous_frame_end) = engine.previous_frame_end.take() {
+                    if let Ok(future) = previous_frame_end.then_signal_fence_and_flush() {
+                        future.wait(None).ok();
+                    }
+                }
+                engine.previous_frame_end = Some(Box::new(sync::now(engine.device.clone())) as Box<_>);
+
+                // Materials go in by index, so the table has to line up with the one the objects were
+                // loaded against before any of it gets written
+                match Scene::load(path).and_then(|reloaded| scene.check_reload(&reloaded).map(|_| reloaded)) {
+                    Ok(reloaded) => for (i, material) in reloaded.materials.iter().enumerate() {
+                        if let Err(e) = renderer.set_material(i, material) {
+                            eprintln!("Failed to reload material {}: {}", i, e);
+                            break;
+                        }
+                    },
+                    Err(e) => eprintln!("Failed to reload {}: {}", path.display(), e),
+                }
+            }
+        }
+        previous_keys = keys.clone();
         if !keys.is_empty() {
             let (yaw_sin, yaw_cos) = (-camera.yaw).sin_cos();
             let forward = Vector3::new(yaw_cos, 0.0, yaw_sin).normalize();
@@ -190,6 +212,17 @@ fn main() {
                     }
                 }
             }
+            // Scrolling up zooms in
+            Event::WindowEvent {
+                event: WindowEvent::MouseWheel { delta, .. },
+                ..
+            } if window_is_focused => {
+                let steps = match delta {
+                    MouseScrollDelta::LineDelta(_, lines) => lines,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / PIXELS_PER_LINE,
+                };
+                camera.zoom(steps);
+            }
             Event::RedrawEventsCleared => {
                 // Clean up unused resources
                 engine.previous_frame_end.as_mut().unwrap().cleanup_finished();
@@ -212,31 +245,11 @@ fn main() {
                     engine.recreate_swapchain = true;
                 }
 
-                // Update view
-                let camera_subbuffer = Arc::new(camera_buffer.next(camera.to_uniform()).unwrap());
-
-                // Create command buffer with a draw command dispatch followed by a copy image to buffer command
+                // Create command buffer with the ray tracing dispatch writing straight into the swapchain image
                 let command_buffer = {
-                    let layout = compute_pipeline.layout().descriptor_set_layout(0).unwrap();
-                    let set = Arc::new(
-                        PersistentDescriptorSet::start(layout.clone())
-                            .add_image(engine.images[image_num].clone()).unwrap() // Image we write to
-                            .add_buffer(camera_subbuffer.clone()).unwrap() // Camera uniform
-                            .add_buffer(spheres_buffer_subbuffer.clone()).unwrap() // Spheres uniform
-                            .add_buffer(lights_buffer_subbuffer.clone()).unwrap() // Lights uniform
-                            .build().unwrap()
-                    );
-
                     let mut command_buffer = AutoCommandBufferBuilder::new(engine.device.clone(), engine.queue.family())
                         .unwrap();
-                    command_buffer.dispatch(
-                        [IMAGE_WIDTH as u32 / 8, IMAGE_HEIGHT as u32 / 8, 1],
-                        compute_pipeline.clone(),
-                        set.clone(),
-                        (),
-                    )
-                        .unwrap();
-
+                    renderer.dispatch(&mut command_buffer, engine.images[image_num].clone(), &camera);
                     command_buffer.build().unwrap()
                 };
 
@@ -270,6 +283,9 @@ fn main() {
 mod cs {
     vulkano_shaders::shader! {
         ty: "compute",
-        path: "src/shaders/shader.comp"
+        path: "src/shaders/shader.comp",
+        types_meta: {
+            #[derive(Clone, Copy, Default)]
+        }
     }
 }
@@ -0,0 +1,133 @@
+use crate::cs;
+use crate::object_traits::Uniform;
+
+/// Procedural patterns a material can mix its two colors with. Values match the PATTERN_ constants
+/// in the shader
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Pattern {
+    Solid = 0,
+    /// Squares of alternating color
+    Checker = 1,
+    /// Bands of alternating color along the texture's u direction
+    Stripes = 2,
+    /// Smooth value noise blending between the two colors
+    Noise = 3,
+}
+
+/// How a surface looks. Lives in the scene's material table, objects refer to it by index so
+/// any number of them can share one
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Material {
+    pub(crate) color: [f32; 4],
+    /// Exponent of the specular highlight, -1 for none
+    pub(crate) specular: f32,
+    /// How much of the color comes from reflections, from 0 to 1
+    pub(crate) reflective: f32,
+    /// How much of the light that isn't reflected goes through the surface, from 0 to 1
+    pub(crate) transparency: f32,
+    /// Index of refraction of whatever is behind the surface, 1.5 for glass and 1.33 for water
+    pub(crate) ior: f32,
+    /// Light the surface gives off by itself, on top of whatever lights it
+    pub(crate) emission: [f32; 3],
+    /// Layer of the scene's textures the color gets multiplied by
+    pub(crate) texture: Option<usize>,
+    pub(crate) pattern: Pattern,
+    /// Color the pattern mixes in with `color`
+    pub(crate) pattern_color: [f32; 3],
+    /// Size of one pattern cell in texture coordinates
+    pub(crate) pattern_scale: f32,
+    /// Layer of the scene's maps holding a tangent space normal map
+    pub(crate) normal_map: Option<usize>,
+    /// Layer of the scene's maps holding a height map in its red channel
+    pub(crate) bump_map: Option<usize>,
+    /// How far the height map tilts the normal
+    pub(crate) bump_strength: f32,
+}
+
+impl Material {
+    /// An opaque material
+    pub fn new(color: &[f32; 4], specular: f32, reflective: f32) -> Self {
+        Material {
+            color: *color,
+            specular,
+            reflective,
+            transparency: 0.0,
+            ior: 1.0,
+            emission: [0.0; 3],
+            texture: None,
+            pattern: Pattern::Solid,
+            pattern_color: [0.0; 3],
+            pattern_scale: 1.0,
+            normal_map: None,
+            bump_map: None,
+            bump_strength: 1.0,
+        }
+    }
+
+    /// Lets `transparency` of the light that isn't reflected through, bending it by `ior`. Light
+    /// passing through takes on the material's color
+    pub fn transparent(mut self, transparency: f32, ior: f32) -> Self {
+        self.transparency = transparency;
+        self.ior = ior;
+        self
+    }
+
+    /// Makes the surface glow. Only path traced mode lets that light fall on other surfaces
+    pub fn emissive(mut self, emission: [f32; 3]) -> Self {
+        self.emission = emission;
+        self
+    }
+
+    /// Multiplies the color by a layer of the scene's textures
+    pub fn textured(mut self, texture: usize) -> Self {
+        self.texture = Some(texture);
+        self
+    }
+
+    /// Mixes `color` into the material's own with a procedural pattern, `scale` texture units to a
+    /// cell
+    pub fn patterned(mut self, pattern: Pattern, color: [f32; 3], scale: f32) -> Self {
+        self.pattern = pattern;
+        self.pattern_color = color;
+        self.pattern_scale = scale;
+        self
+    }
+
+    /// Tilts the surface normal by a layer of the scene's maps, with green pointing up the image
+    /// like OpenGL expects
+    pub fn normal_mapped(mut self, map: usize) -> Self {
+        self.normal_map = Some(map);
+        self
+    }
+
+    /// Tilts the surface normal away from the higher parts of a height map, a layer of the scene's
+    /// maps
+    pub fn bump_mapped(mut self, map: usize, strength: f32) -> Self {
+        self.bump_map = Some(map);
+        self.bump_strength = strength;
+        self
+    }
+}
+
+impl Uniform for Material {
+    type Uniform = cs::ty::Material;
+
+    fn to_uniform(&self) -> Self::Uniform {
+        cs::ty::Material {
+            color: self.color,
+            specular: self.specular,
+            reflective: self.reflective,
+            transparency: self.transparency,
+            ior: self.ior,
+            emission: [self.emission[0], self.emission[1], self.emission[2], 0.0],
+            patternColor: [self.pattern_color[0], self.pattern_color[1], self.pattern_color[2], 0.0],
+            texture: self.texture.map_or(-1, |texture| texture as i32),
+            pattern: self.pattern as i32,
+            patternScale: self.pattern_scale,
+            normalMap: self.normal_map.map_or(-1, |texture| texture as i32),
+            bumpMap: self.bump_map.map_or(-1, |texture| texture as i32),
+            bumpStrength: self.bump_strength,
+            padding: [0.0; 2],
+        }
+    }
+}
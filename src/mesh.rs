@@ -0,0 +1,81 @@
+use cgmath::{InnerSpace, Vector2, Vector3, Zero};
+
+use crate::cs;
+
+/// A triangle mesh sharing one material across all of its faces
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    pub(crate) positions: Vec<Vector3<f32>>,
+    pub(crate) normals: Vec<Vector3<f32>>,
+    /// Texture coordinates, one per position
+    pub(crate) uvs: Vec<Vector2<f32>>,
+    // Corners of each triangle as indices into positions and normals, counter-clockwise
+    pub(crate) triangles: Vec<[u32; 3]>,
+    /// Index into the scene's materials
+    pub(crate) material: usize,
+}
+
+impl Mesh {
+    /// Builds a mesh from indexed triangles. `normals` holds one normal per position, or is empty
+    /// to have smooth normals computed from the faces around each vertex. Same goes for `uvs`,
+    /// which are all zero when left empty
+    pub fn new(
+        positions: Vec<Vector3<f32>>,
+        normals: Vec<Vector3<f32>>,
+        uvs: Vec<Vector2<f32>>,
+        triangles: Vec<[u32; 3]>,
+        material: usize,
+    ) -> Self {
+        let normals = if normals.is_empty() {
+            smooth_normals(&positions, &triangles)
+        } else {
+            normals.into_iter().map(|n| n.normalize()).collect()
+        };
+        let uvs = if uvs.is_empty() {
+            vec![Vector2::zero(); positions.len()]
+        } else {
+            uvs
+        };
+        Mesh {
+            positions,
+            normals,
+            uvs,
+            triangles,
+            material,
+        }
+    }
+
+    /// Scales the mesh about its origin and then moves it by `offset`
+    pub fn transform(&mut self, offset: Vector3<f32>, scale: f32) {
+        for position in &mut self.positions {
+            *position = *position * scale + offset;
+        }
+    }
+
+    /// Vertices as the shader wants them, in the same order `triangles` indexes them
+    pub fn vertex_uniforms(&self) -> impl Iterator<Item = cs::ty::Vertex> + '_ {
+        self.positions.iter().zip(&self.normals).zip(&self.uvs).map(|((position, normal), uv)| cs::ty::Vertex {
+            position: position.extend(1.0).into(),
+            normal: normal.extend(0.0).into(),
+            uv: (*uv).into(),
+            padding: [0.0; 2],
+        })
+    }
+}
+
+// Sums the face normals around every vertex. The cross product's length is twice the triangle's
+// area, so big faces get more say than slivers
+pub(crate) fn smooth_normals(positions: &[Vector3<f32>], triangles: &[[u32; 3]]) -> Vec<Vector3<f32>> {
+    let mut normals = vec![Vector3::zero(); positions.len()];
+    for &[a, b, c] in triangles {
+        let (a, b, c) = (a as usize, b as usize, c as usize);
+        let face_normal = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+        normals[a] += face_normal;
+        normals[b] += face_normal;
+        normals[c] += face_normal;
+    }
+    // Vertices no triangle uses, or only degenerate ones, would otherwise end up NaN
+    normals.into_iter()
+        .map(|n| if n.magnitude2() > 0.0 { n.normalize() } else { Vector3::unit_y() })
+        .collect()
+}
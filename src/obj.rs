@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use cgmath::{Vector2, Vector3};
+
+use crate::mesh::{self, Mesh};
+
+/// Loads every model in a Wavefront OBJ file into a single mesh with the given material. Faces
+/// with more than three corners are triangulated, models without normals get smooth ones and
+/// models without texture coordinates get zeros. Materials from .mtl files are ignored
+pub fn load_obj(path: &Path, material: usize) -> Result<Mesh, tobj::LoadError> {
+    let (models, _materials) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)?;
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut triangles = Vec::new();
+    for model in models {
+        let model = model.mesh;
+        let base = positions.len() as u32;
+        let model_positions: Vec<_> = model.positions.chunks_exact(3)
+            .map(|p| Vector3::new(p[0], p[1], p[2]))
+            .collect();
+        let model_triangles: Vec<_> = model.indices.chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect();
+
+        if model.normals.len() == model.positions.len() {
+            normals.extend(model.normals.chunks_exact(3).map(|n| Vector3::new(n[0], n[1], n[2])));
+        } else {
+            normals.extend(mesh::smooth_normals(&model_positions, &model_triangles));
+        }
+        // OBJ counts v up from the bottom of the image, textures get sampled from the top down
+        if model.texcoords.len() / 2 == model_positions.len() {
+            uvs.extend(model.texcoords.chunks_exact(2).map(|t| Vector2::new(t[0], 1.0 - t[1])));
+        } else {
+            uvs.extend(model_positions.iter().map(|_| Vector2::new(0.0, 0.0)));
+        }
+        positions.extend(model_positions);
+        triangles.extend(model_triangles.iter().map(|[a, b, c]| [base + a, base + b, base + c]));
+    }
+
+    Ok(Mesh::new(positions, normals, uvs, triangles, material))
+}
@@ -0,0 +1,380 @@
+use crate::bvh::Bvh;
+use crate::cs;
+use crate::camera::Camera;
+use crate::material::Material;
+use crate::object_traits::Uniform;
+use crate::scene::{RenderMode, RenderSettings, Scene};
+
+use image::RgbaImage;
+use std::fmt;
+use std::iter;
+use std::sync::Arc;
+use vulkano::buffer::cpu_access::WriteLockError;
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::pipeline_layout::PipelineLayout;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::{Device, Queue};
+use vulkano::format::Format;
+use vulkano::half::f16;
+use vulkano::image::{Dimensions, ImageUsage, ImageViewAccess, ImmutableImage, MipmapsCount, StorageImage};
+use vulkano::instance::QueueFamily;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode};
+use vulkano::sync::GpuFuture;
+
+/// Owns the ray tracing pipeline and the scene buffers it reads from. Independent of where the
+/// result ends up, so both the window and headless rendering go through here.
+///
+/// In path traced mode, and in classic mode when the scene asks for it, every dispatch adds
+/// another frame to an accumulation image and writes the average so far. The average starts over
+/// whenever anything about the camera changes, the mode changes or the output size does. Apart
+/// from its materials, see `set_material`, the scene itself can't change, a new scene needs a new
+/// `Renderer`
+pub struct Renderer {
+    device: Arc<Device>,
+    pipeline: Arc<ComputePipeline<PipelineLayout<cs::Layout>>>,
+    camera_buffer: CpuBufferPool<cs::ty::Camera>,
+    spheres_buffer: Arc<CpuAccessibleBuffer<[cs::ty::Sphere]>>,
+    lights_buffer: Arc<CpuAccessibleBuffer<[cs::ty::Light]>>,
+    vertices_buffer: Arc<CpuAccessibleBuffer<[cs::ty::Vertex]>>,
+    triangles_buffer: Arc<CpuAccessibleBuffer<[cs::ty::Triangle]>>,
+    materials_buffer: Arc<CpuAccessibleBuffer<[cs::ty::Material]>>,
+    bvh_nodes_buffer: Arc<CpuAccessibleBuffer<[cs::ty::BvhNode]>>,
+    bvh_primitives_buffer: Arc<CpuAccessibleBuffer<[cs::ty::BvhPrimitive]>>,
+    planes_buffer: Arc<CpuAccessibleBuffer<[cs::ty::Plane]>>,
+    shapes_buffer: Arc<CpuAccessibleBuffer<[cs::ty::Shape]>>,
+    environment_buffer: Arc<CpuAccessibleBuffer<[cs::ty::Environment]>>,
+    textures: Arc<ImmutableImage<Format>>,
+    maps: Arc<ImmutableImage<Format>>,
+    environment_image: Arc<ImmutableImage<Format>>,
+    sampler: Arc<Sampler>,
+    environment_sampler: Arc<Sampler>,
+    light_count: usize,
+    plane_count: usize,
+    primitive_count: usize,
+    mode: RenderMode,
+    // Samples per pixel and where they go
+    settings: RenderSettings,
+    accumulation: Option<Arc<StorageImage<Format>>>,
+    // Frames summed up in accumulation so far
+    frame: u32,
+    // Camera the accumulated frames were traced from
+    last_camera: Option<Camera>,
+}
+
+/// Why `Renderer::set_material` couldn't replace a material
+#[derive(Debug)]
+pub enum MaterialError {
+    OutOfRange { index: usize, count: usize },
+    InUse(WriteLockError),
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaterialError::OutOfRange { index, count } =>
+                write!(f, "material {} is out of range, the scene has {}", index, count),
+            MaterialError::InUse(e) => write!(f, "materials are still in use by the GPU: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MaterialError {}
+
+impl Renderer {
+    /// `queue` is only used to upload the scene's textures, and has to be done with that before
+    /// `new` returns
+    pub fn new(queue: Arc<Queue>, scene: &Scene) -> Self {
+        let device = queue.device().clone();
+        let shader = cs::Shader::load(device.clone()).expect("failed to create shader module");
+
+        let pipeline = Arc::new(
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &(), None)
+                .expect("failed to create compute pipeline")
+        );
+
+        // Initialize camera uniform buffer
+        let camera_buffer = CpuBufferPool::<cs::ty::Camera>::new(device.clone(), BufferUsage::all());
+        // Initialize spheres and lights storage buffers
+        let spheres_buffer = storage_buffer(
+            device.clone(),
+            scene.spheres.iter().map(Uniform::to_uniform).collect(),
+        );
+        let lights_buffer = storage_buffer(
+            device.clone(),
+            scene.lights.iter().map(Uniform::to_uniform).collect(),
+        );
+
+        // All meshes share one vertex and one triangle buffer, so each mesh's indices get moved past
+        // the vertices of the meshes before it
+        let mut vertices = Vec::new();
+        let mut triangles = Vec::new();
+        for mesh in &scene.meshes {
+            let base = vertices.len() as u32;
+            vertices.extend(mesh.vertex_uniforms());
+            triangles.extend(mesh.triangles.iter().map(|[a, b, c]| cs::ty::Triangle {
+                a: base + a,
+                b: base + b,
+                c: base + c,
+                material: mesh.material as i32,
+            }));
+        }
+        let vertices_buffer = storage_buffer(device.clone(), vertices);
+        let triangles_buffer = storage_buffer(device.clone(), triangles);
+        let materials_buffer = storage_buffer(
+            device.clone(),
+            scene.materials.iter().map(Uniform::to_uniform).collect(),
+        );
+
+        // Planes have no bounds to put in the BVH, the shader checks every one of them instead
+        let planes_buffer = storage_buffer(
+            device.clone(),
+            scene.planes.iter().map(Uniform::to_uniform).collect(),
+        );
+        let shapes_buffer = storage_buffer(
+            device.clone(),
+            scene.shapes.iter().map(Uniform::to_uniform).collect(),
+        );
+
+        // The shader only ever finds spheres, triangles and shapes through the BVH
+        let bvh = Bvh::build(scene);
+        let bvh_nodes_buffer = storage_buffer(
+            device.clone(),
+            bvh.nodes.iter().map(Uniform::to_uniform).collect(),
+        );
+        let bvh_primitives_buffer = storage_buffer(
+            device.clone(),
+            bvh.primitives.iter().map(Uniform::to_uniform).collect(),
+        );
+
+        let environment_buffer = storage_buffer(device.clone(), vec![scene.environment.to_uniform()]);
+
+        let (environment_image, environment_sampler) = environment_image(queue.clone(), scene);
+        // Color textures are sRGB so the GPU turns them linear before lighting them. Normal and
+        // height maps hold plain numbers, those have to come through unchanged
+        let textures = texture_array(queue.clone(), &scene.textures, Format::R8G8B8A8Srgb);
+        let maps = texture_array(queue.clone(), &scene.maps, Format::R8G8B8A8Unorm);
+        let sampler = Sampler::simple_repeat_linear_no_mipmap(queue.device().clone());
+
+        Self {
+            device,
+            pipeline,
+            camera_buffer,
+            spheres_buffer,
+            lights_buffer,
+            vertices_buffer,
+            triangles_buffer,
+            materials_buffer,
+            bvh_nodes_buffer,
+            bvh_primitives_buffer,
+            planes_buffer,
+            shapes_buffer,
+            environment_buffer,
+            textures,
+            maps,
+            environment_image,
+            sampler,
+            environment_sampler,
+            light_count: scene.lights.len(),
+            plane_count: scene.planes.len(),
+            primitive_count: bvh.primitives.len(),
+            mode: scene.render.mode,
+            settings: scene.render,
+            accumulation: None,
+            frame: 0,
+            last_camera: None,
+        }
+    }
+
+    pub fn mode(&self) -> RenderMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: RenderMode) {
+        self.mode = mode;
+        self.frame = 0;
+    }
+
+    /// Replaces the material at `index` in the scene's material table, changing every object that
+    /// uses it from the next dispatch on. Fails for an index past the end of the table, or while a
+    /// dispatch that reads the materials is still running on the GPU
+    pub fn set_material(&mut self, index: usize, material: &Material) -> Result<(), MaterialError> {
+        let mut materials = self.materials_buffer.write().map_err(MaterialError::InUse)?;
+        let count = materials.len();
+        *materials.get_mut(index).ok_or(MaterialError::OutOfRange { index, count })? = material.to_uniform();
+        // Frames traced with the old material would otherwise linger in the average
+        self.frame = 0;
+        Ok(())
+    }
+
+    /// Records the compute dispatch that traces the scene from `camera` into `image`, covering the
+    /// whole image whatever its size
+    pub fn dispatch<I>(&mut self, command_buffer: &mut AutoCommandBufferBuilder, image: I, camera: &Camera)
+        where I: ImageViewAccess + Send + Sync + 'static
+    {
+        let dimensions = image.dimensions();
+        let accumulation = self.accumulation_image(dimensions.width(), dimensions.height());
+
+        if self.last_camera.as_ref() != Some(camera) {
+            self.frame = 0;
+            self.last_camera = Some(camera.clone());
+        }
+
+        let push_constants = cs::ty::PushConstants {
+            lightCount: self.light_count as i32,
+            planeCount: self.plane_count as i32,
+            primitiveCount: self.primitive_count as i32,
+            width: dimensions.width(),
+            height: dimensions.height(),
+            mode: self.mode as i32,
+            frame: self.frame,
+            samples: self.settings.samples as i32,
+            sampling: self.settings.sampling(self.mode) as i32,
+            accumulate: self.settings.accumulate as i32,
+            stereo: self.settings.stereo as i32,
+        };
+        self.frame += 1;
+
+        // Update view
+        let camera_subbuffer = Arc::new(self.camera_buffer.next(camera.to_uniform()).unwrap());
+
+        let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
+        let set = Arc::new(
+            PersistentDescriptorSet::start(layout.clone())
+                .add_image(image).unwrap() // Image we write to
+                .add_buffer(camera_subbuffer).unwrap() // Camera uniform
+                .add_buffer(self.spheres_buffer.clone()).unwrap() // Spheres storage buffer
+                .add_buffer(self.lights_buffer.clone()).unwrap() // Lights storage buffer
+                .add_buffer(self.vertices_buffer.clone()).unwrap() // Mesh vertices
+                .add_buffer(self.triangles_buffer.clone()).unwrap() // Mesh triangles
+                .add_buffer(self.materials_buffer.clone()).unwrap() // Materials storage buffer
+                .add_buffer(self.bvh_nodes_buffer.clone()).unwrap() // BVH nodes
+                .add_buffer(self.bvh_primitives_buffer.clone()).unwrap() // What the BVH leaves point at
+                .add_image(accumulation).unwrap() // Path traced frames so far
+                .add_buffer(self.planes_buffer.clone()).unwrap() // Planes storage buffer
+                .add_buffer(self.shapes_buffer.clone()).unwrap() // Boxes, disks and cylinders
+                .add_sampled_image(self.textures.clone(), self.sampler.clone()).unwrap() // Color textures
+                .add_buffer(self.environment_buffer.clone()).unwrap() // What rays that miss see
+                .add_sampled_image(self.environment_image.clone(), self.environment_sampler.clone()).unwrap() // Sky image
+                .add_sampled_image(self.maps.clone(), self.sampler.clone()).unwrap() // Normal and height maps
+                .build().unwrap()
+        );
+
+        // One invocation per pixel in 8x8 work groups, rounded up so sizes that aren't a multiple of
+        // 8 still get their last row and column
+        command_buffer.dispatch(
+            [(push_constants.width + 7) / 8, (push_constants.height + 7) / 8, 1],
+            self.pipeline.clone(),
+            set,
+            push_constants,
+        )
+            .unwrap();
+    }
+
+    // The accumulation image has to match the output size, so it's (re)created on first use and
+    // whenever the output gets resized
+    fn accumulation_image(&mut self, width: u32, height: u32) -> Arc<StorageImage<Format>> {
+        if let Some(image) = &self.accumulation {
+            let dimensions = image.dimensions();
+            if dimensions.width() == width && dimensions.height() == height {
+                return image.clone();
+            }
+        }
+
+        let image = StorageImage::with_usage(
+            self.device.clone(),
+            Dimensions::Dim2d { width, height },
+            Format::R32G32B32A32Sfloat,
+            ImageUsage {
+                storage: true,
+                ..ImageUsage::none()
+            },
+            iter::empty::<QueueFamily>(),
+        ).expect("failed to create accumulation image");
+        self.accumulation = Some(image.clone());
+        self.frame = 0;
+        image
+    }
+}
+
+// Uploads textures, all the same size, as layers of one image. Like buffers, an empty list gets a
+// single layer the shader never reads
+fn texture_array(queue: Arc<Queue>, textures: &[RgbaImage], format: Format) -> Arc<ImmutableImage<Format>> {
+    let (width, height) = textures.first().map_or((1, 1), |texture| texture.dimensions());
+    let layers = textures.len().max(1) as u32;
+    let texels: Vec<u8> = if textures.is_empty() {
+        vec![255; 4]
+    } else {
+        textures.iter().flat_map(|texture| texture.as_raw().iter().copied()).collect()
+    };
+
+    let (image, upload) = ImmutableImage::from_iter(
+        texels.into_iter(),
+        Dimensions::Dim2dArray { width, height, array_layers: layers },
+        MipmapsCount::One,
+        format,
+        queue,
+    ).expect("failed to create texture image");
+    upload.then_signal_fence_and_flush()
+        .and_then(|future| future.wait(None))
+        .expect("failed to upload textures");
+    image
+}
+
+// Uploads the scene's sky image as floats so it can be brighter than white, and the sampler that
+// reads it. Scenes without one get a single black texel the shader never reads
+fn environment_image(queue: Arc<Queue>, scene: &Scene) -> (Arc<ImmutableImage<Format>>, Arc<Sampler>) {
+    // Devices only have to filter 16 bit floats linearly, 32 bit ones are optional. Anything
+    // brighter than half floats go is clamped rather than turning infinite
+    let half = |c: f32| f16::from_f32(c.min(f16::MAX.to_f32()));
+    let (width, height, texels) = match scene.environment.image() {
+        Some(image) => (
+            image.width(),
+            image.height(),
+            image.pixels().map(|pixel| [half(pixel[0]), half(pixel[1]), half(pixel[2]), f16::ONE]).collect(),
+        ),
+        None => (1, 1, vec![[f16::ZERO; 4]]),
+    };
+
+    let (image, upload) = ImmutableImage::from_iter(
+        texels.into_iter(),
+        Dimensions::Dim2d { width, height },
+        MipmapsCount::One,
+        Format::R16G16B16A16Sfloat,
+        queue.clone(),
+    ).expect("failed to create environment image");
+    upload.then_signal_fence_and_flush()
+        .and_then(|future| future.wait(None))
+        .expect("failed to upload environment image");
+
+    // Going around the sky wraps, but past the poles it doesn't. Repeating v there would blend the
+    // top row with the bottom one
+    let sampler = Sampler::new(
+        queue.device().clone(),
+        Filter::Linear,
+        Filter::Linear,
+        MipmapMode::Nearest,
+        SamplerAddressMode::Repeat,
+        SamplerAddressMode::ClampToEdge,
+        SamplerAddressMode::ClampToEdge,
+        0.0,
+        1.0,
+        0.0,
+        1.0,
+    ).expect("failed to create environment sampler");
+    (image, sampler)
+}
+
+// Vulkan doesn't allow empty buffers, so an empty list gets a single zeroed element that the shader
+// never reads. The real element counts are passed to the shader separately
+fn storage_buffer<T>(device: Arc<Device>, mut data: Vec<T>) -> Arc<CpuAccessibleBuffer<[T]>>
+    where T: Default + Send + Sync + 'static
+{
+    if data.is_empty() {
+        data.push(T::default());
+    }
+    CpuAccessibleBuffer::from_iter(device, BufferUsage::all(), false, data.into_iter())
+        .expect("failed to create storage buffer")
+}
@@ -0,0 +1,1220 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use cgmath::{Deg, Vector3};
+use image::RgbaImage;
+use serde::de::{self, MapAccess, Visitor};
+use serde::de::value::MapAccessDeserializer;
+use serde::{Deserialize, Deserializer};
+
+use crate::camera::{Camera, Projection, MAX_FISHEYE_FOV};
+use crate::environment::{self, Background, Environment};
+use crate::light::{Light, LightType, DEFAULT_AREA_SAMPLES};
+use crate::material::{Material, Pattern};
+use crate::mesh::Mesh;
+use crate::obj;
+use crate::shape::{Cuboid, Cylinder, Disk, Plane, Shape};
+use crate::sphere::Sphere;
+use crate::texture;
+
+/// Scene that ships with the binary, used when no scene file is given on the command line
+pub const DEFAULT_SCENE: &str = include_str!("../scenes/demo.ron");
+
+/// Everything needed to render a frame, built from a scene file
+pub struct Scene {
+    pub camera: Camera,
+    pub spheres: Vec<Sphere>,
+    pub meshes: Vec<Mesh>,
+    pub planes: Vec<Plane>,
+    /// Boxes, disks and cylinders, in that order
+    pub shapes: Vec<Shape>,
+    pub lights: Vec<Light>,
+    /// Every object's material is an index into here
+    pub materials: Vec<Material>,
+    /// Color textures materials refer to by index, all the same size. Stored in sRGB, like images
+    /// usually are
+    pub textures: Vec<RgbaImage>,
+    /// Normal and height maps materials refer to by index, all the same size. Unlike textures
+    /// these hold plain numbers, read as they are
+    pub maps: Vec<RgbaImage>,
+    /// What rays that miss everything see
+    pub environment: Environment,
+    pub render: RenderSettings,
+    /// Where the materials and textures came from, see `check_reload`
+    layout: MaterialLayout,
+}
+
+// Which named material each entry of the material table is, None for inline ones, and the files
+// the textures and maps were loaded from, in table order
+#[derive(Debug, PartialEq)]
+struct MaterialLayout {
+    names: Vec<Option<String>>,
+    textures: Vec<PathBuf>,
+    maps: Vec<PathBuf>,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSettings {
+    pub vsync: bool,
+    /// Size of the window, or of the image when rendering headless
+    pub width: u32,
+    pub height: u32,
+    /// Mode to start in, the window can switch between them at runtime
+    pub mode: RenderMode,
+    /// Rays traced through each pixel every frame, averaged together
+    pub samples: u32,
+    /// Where in the pixel those rays go. Left out, classic mode uses a grid and path tracing
+    /// stratifies, see `sampling`
+    #[serde(deserialize_with = "some")]
+    pub sampling: Option<Sampling>,
+    /// Average classic frames while the camera holds still, the way path traced ones always are.
+    /// Only adds anything with a sampling that changes from frame to frame. Always on for cameras
+    /// with an aperture
+    pub accumulate: bool,
+    /// Whether to render a view for each eye, and how to fit them into the image
+    pub stereo: Stereo,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            vsync: true,
+            width: 1920,
+            height: 1080,
+            mode: RenderMode::Classic,
+            samples: 1,
+            sampling: None,
+            accumulate: false,
+            stereo: Stereo::Mono,
+        }
+    }
+}
+
+impl RenderSettings {
+    /// The sampling to use in `mode`. A single grid sample is one fixed ray per pixel, so classic
+    /// mode stays noise free unless asked otherwise. Path tracing averages frames anyway, so it
+    /// jitters its samples to anti-alias for free
+    pub fn sampling(&self, mode: RenderMode) -> Sampling {
+        self.sampling.unwrap_or(match mode {
+            RenderMode::Classic => Sampling::Grid,
+            RenderMode::PathTraced => Sampling::Stratified,
+        })
+    }
+
+    /// Whether frames get averaged while the camera holds still
+    pub fn accumulates(&self, mode: RenderMode) -> bool {
+        mode == RenderMode::PathTraced || self.accumulate
+    }
+}
+
+/// How the shader turns rays into colors. Values match the MODE_ constants in the shader
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum RenderMode {
+    /// Whitted style ray tracing with hard shadows and mirror reflections. Noise free from the
+    /// first frame
+    Classic = 0,
+    /// Monte Carlo path tracing with soft indirect light, averaged over frames while the camera
+    /// holds still
+    PathTraced = 1,
+}
+
+impl RenderMode {
+    pub fn toggled(self) -> Self {
+        match self {
+            RenderMode::Classic => RenderMode::PathTraced,
+            RenderMode::PathTraced => RenderMode::Classic,
+        }
+    }
+}
+
+/// Layouts for stereo images. Values match the STEREO_ constants in the shader
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum Stereo {
+    /// A single view filling the image
+    Mono = 0,
+    /// Left eye's view in the left half of the image, right eye's in the right half. Views keep
+    /// their pixels square unless the camera's aspect says otherwise. Displays that stretch each
+    /// half back out to the full width want the aspect of the whole image instead
+    SideBySide = 1,
+    /// Left eye's view in the top half, right eye's in the bottom half
+    TopBottom = 2,
+}
+
+/// Where a pixel's samples go within it. Values match the SAMPLING_ constants in the shader
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum Sampling {
+    /// Centers of an evenly spaced grid, the same every frame
+    Grid = 0,
+    /// A random point in each cell of the grid, different every frame
+    Stratified = 1,
+    /// Halton sequence in bases 2 and 3, carrying on from one frame to the next
+    Halton = 2,
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(PathBuf, std::io::Error),
+    Parse(ron::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            SceneError::Parse(e) => write!(f, "malformed scene file: {}", e),
+            SceneError::Invalid(message) => write!(f, "invalid scene: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl Scene {
+    pub fn load(path: &Path) -> Result<Self, SceneError> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| SceneError::Io(path.to_path_buf(), e))?;
+        // Mesh and texture paths are relative to the scene file
+        Self::parse(&source, path.parent().unwrap_or_else(|| Path::new("")))
+    }
+
+    // Every object has to point at a material that exists, the shader doesn't check
+    fn check_materials(&self) -> Result<(), SceneError> {
+        let check = |kind: &str, i: usize, material: usize| if material < self.materials.len() {
+            Ok(())
+        } else {
+            Err(SceneError::Invalid(format!(
+                "{} {}: material {} is out of range, the scene has {}", kind, i, material, self.materials.len(),
+            )))
+        };
+        for (i, sphere) in self.spheres.iter().enumerate() {
+            check("sphere", i, sphere.material)?;
+        }
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            check("mesh", i, mesh.material)?;
+        }
+        for (i, plane) in self.planes.iter().enumerate() {
+            check("plane", i, plane.material)?;
+        }
+        for (i, shape) in self.shapes.iter().enumerate() {
+            check("shape", i, shape.material())?;
+        }
+        Ok(())
+    }
+
+    /// Checks that `reloaded` has the same materials in the same order, and the same textures, so
+    /// its materials can replace this scene's index for index. Objects keep the material indices
+    /// they were loaded with and the renderer the textures, adding, removing or renaming a
+    /// material or texture takes a restart
+    pub fn check_reload(&self, reloaded: &Scene) -> Result<(), SceneError> {
+        let (old, new) = (&self.layout, &reloaded.layout);
+        if new.names.len() != old.names.len() {
+            return Err(SceneError::Invalid(format!(
+                "the scene now has {} materials instead of {}", new.names.len(), old.names.len(),
+            )));
+        }
+        let describe = |name: &Option<String>| name.as_ref().map_or_else(|| "an inline material".to_string(), |name| format!("{:?}", name));
+        if let Some(i) = (0..old.names.len()).find(|&i| new.names[i] != old.names[i]) {
+            return Err(SceneError::Invalid(format!(
+                "material {} is now {} instead of {}", i, describe(&new.names[i]), describe(&old.names[i]),
+            )));
+        }
+        if new.textures != old.textures || new.maps != old.maps {
+            return Err(SceneError::Invalid("the materials use different textures or maps".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Every triangle of every mesh along with the mesh it belongs to. This is the order the
+    /// renderer numbers triangles in
+    pub fn triangles(&self) -> impl Iterator<Item = (&Mesh, [u32; 3])> {
+        self.meshes.iter().flat_map(|mesh| mesh.triangles.iter().map(move |&triangle| (mesh, triangle)))
+    }
+
+    fn parse(source: &str, base_dir: &Path) -> Result<Self, SceneError> {
+        let description: SceneDescription = ron::de::from_str(source).map_err(SceneError::Parse)?;
+        description.build(base_dir)
+    }
+}
+
+impl FromStr for Scene {
+    type Err = SceneError;
+
+    // Without a file to be relative to, mesh and texture paths are relative to the working directory
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        Self::parse(source, Path::new(""))
+    }
+}
+
+// What the scene file looks like on disk. Kept separate from the runtime types so the file format
+// can stay friendly (degrees, RGB colors) while the runtime types stay shaped for the GPU.
+#[derive(Debug, Deserialize)]
+#[serde(rename = "Scene", deny_unknown_fields)]
+struct SceneDescription {
+    #[serde(default)]
+    camera: CameraDescription,
+    // Materials objects can share by naming them
+    #[serde(default)]
+    materials: BTreeMap<String, MaterialDescription>,
+    #[serde(default)]
+    spheres: Vec<SphereDescription>,
+    #[serde(default)]
+    meshes: Vec<MeshDescription>,
+    #[serde(default)]
+    planes: Vec<PlaneDescription>,
+    #[serde(default)]
+    boxes: Vec<BoxDescription>,
+    #[serde(default)]
+    disks: Vec<DiskDescription>,
+    #[serde(default)]
+    cylinders: Vec<CylinderDescription>,
+    #[serde(default)]
+    lights: Vec<LightDescription>,
+    #[serde(default)]
+    environment: EnvironmentDescription,
+    #[serde(default)]
+    render: RenderSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CameraDescription {
+    position: [f32; 3],
+    yaw: f32,
+    pitch: f32,
+    speed: f32,
+    sensitivity: f32,
+    projection: Projection,
+    // Vertical field of view in degrees
+    fov: f32,
+    // Height of an orthographic view in world units
+    ortho_height: f32,
+    // Width over height, 0 to match the window or image
+    aspect: f32,
+    near: f32,
+    // Lens radius, 0 keeps everything in focus
+    aperture: f32,
+    focus_distance: f32,
+    // For stereo rendering
+    eye_separation: f32,
+    convergence: f32,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        let camera = Camera::from_origin();
+        Self {
+            position: camera.position.into(),
+            yaw: Deg::from(camera.yaw).0,
+            pitch: Deg::from(camera.pitch).0,
+            speed: camera.speed,
+            sensitivity: camera.sensitivity,
+            projection: camera.projection,
+            fov: Deg::from(camera.fov).0,
+            ortho_height: camera.ortho_height,
+            aspect: camera.aspect,
+            near: camera.near,
+            aperture: camera.aperture,
+            focus_distance: camera.focus_distance,
+            eye_separation: camera.eye_separation,
+            convergence: camera.convergence,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SphereDescription {
+    center: [f32; 3],
+    radius: f32,
+    material: MaterialSource,
+}
+
+// A Wavefront OBJ model, scaled about its own origin and then moved to `position`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshDescription {
+    path: PathBuf,
+    #[serde(default)]
+    position: [f32; 3],
+    #[serde(default = "unit_scale")]
+    scale: f32,
+    material: MaterialSource,
+}
+
+// Infinite plane through `point`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlaneDescription {
+    point: [f32; 3],
+    normal: [f32; 3],
+    material: MaterialSource,
+}
+
+// Axis-aligned box between two opposite corners
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BoxDescription {
+    min: [f32; 3],
+    max: [f32; 3],
+    material: MaterialSource,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DiskDescription {
+    center: [f32; 3],
+    normal: [f32; 3],
+    radius: f32,
+    material: MaterialSource,
+}
+
+// Capped cylinder standing on `base`, reaching up to `base + axis`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CylinderDescription {
+    base: [f32; 3],
+    axis: [f32; 3],
+    radius: f32,
+    material: MaterialSource,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDescription {
+    color: [f32; 3],
+    #[serde(default = "no_specular")]
+    specular: f32,
+    #[serde(default)]
+    reflective: f32,
+    #[serde(default)]
+    transparency: f32,
+    #[serde(default = "glass_ior")]
+    ior: f32,
+    // Light given off by the surface itself, per RGB channel. Can go above 1
+    #[serde(default)]
+    emission: [f32; 3],
+    // Image the color gets multiplied by
+    #[serde(default, deserialize_with = "some")]
+    texture: Option<PathBuf>,
+    #[serde(default, deserialize_with = "some")]
+    pattern: Option<PatternDescription>,
+    // Tangent space normal map, green pointing up the image
+    #[serde(default, deserialize_with = "some")]
+    normal_map: Option<PathBuf>,
+    // Height map, read from the red channel
+    #[serde(default, deserialize_with = "some")]
+    bump_map: Option<PathBuf>,
+    #[serde(default = "unit_scale")]
+    bump_strength: f32,
+}
+
+// Procedural pattern mixing `color` into the material's own, `scale` texture units to a cell
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+enum PatternDescription {
+    Checker {
+        color: [f32; 3],
+        #[serde(default = "unit_scale")]
+        scale: f32,
+    },
+    Stripes {
+        color: [f32; 3],
+        #[serde(default = "unit_scale")]
+        scale: f32,
+    },
+    Noise {
+        color: [f32; 3],
+        #[serde(default = "unit_scale")]
+        scale: f32,
+    },
+}
+
+// The background, and whether reflections and bounced light see it too
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EnvironmentDescription {
+    background: BackgroundDescription,
+    lighting: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+enum BackgroundDescription {
+    Solid {
+        color: [f32; 3],
+    },
+    // Sky color straight up, fading to ground color straight down
+    Gradient {
+        top: [f32; 3],
+        bottom: [f32; 3],
+    },
+    // Equirectangular Radiance .hdr image
+    Image {
+        path: PathBuf,
+        #[serde(default = "unit_scale")]
+        intensity: f32,
+    },
+}
+
+impl Default for BackgroundDescription {
+    fn default() -> Self {
+        BackgroundDescription::Solid { color: [0.0; 3] }
+    }
+}
+
+// Either the name of one of the scene's materials, or a material of the object's own
+#[derive(Debug)]
+enum MaterialSource {
+    Named(String),
+    Inline(MaterialDescription),
+}
+
+// Written out rather than derived with serde(untagged), which buffers the material first and can't
+// tell RON enums like patterns apart in the buffered copy
+impl<'de> Deserialize<'de> for MaterialSource {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        struct SourceVisitor;
+
+        impl<'de> Visitor<'de> for SourceVisitor {
+            type Value = MaterialSource;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a material name or a material")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Self::Value, E> {
+                Ok(MaterialSource::Named(name.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                MaterialDescription::deserialize(MapAccessDeserializer::new(map)).map(MaterialSource::Inline)
+            }
+        }
+
+        deserializer.deserialize_any(SourceVisitor)
+    }
+}
+
+// Lets optional fields be written without Some(...)
+fn some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de>
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+fn no_specular() -> f32 {
+    -1.0
+}
+
+fn glass_ior() -> f32 {
+    1.5
+}
+
+fn unit_scale() -> f32 {
+    1.0
+}
+
+// Lights with a position get divided by `constant + linear * d + quadratic * d * d` at distance d.
+// Without any, they reach everywhere at full strength
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AttenuationDescription {
+    constant: f32,
+    linear: f32,
+    quadratic: f32,
+}
+
+impl Default for AttenuationDescription {
+    fn default() -> Self {
+        AttenuationDescription {
+            constant: 1.0,
+            linear: 0.0,
+            quadratic: 0.0,
+        }
+    }
+}
+
+// How lights with a position fade with distance. Written out as `falloff: (attenuation: (...),
+// range: ...)`, since ron can't flatten it into the light's own fields
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FalloffDescription {
+    attenuation: AttenuationDescription,
+    // Distance past which the light reaches nothing, 0 for no limit
+    range: f32,
+}
+
+fn white() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn area_samples() -> u32 {
+    DEFAULT_AREA_SAMPLES
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+enum LightDescription {
+    Ambient {
+        intensity: f32,
+        #[serde(default = "white")]
+        color: [f32; 3],
+    },
+    Point {
+        intensity: f32,
+        position: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        #[serde(default)]
+        falloff: FalloffDescription,
+    },
+    // Lights everything from far away, shining along `direction` the way a spot light does
+    Directional {
+        intensity: f32,
+        direction: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+    },
+    Sphere {
+        intensity: f32,
+        #[serde(default = "white")]
+        color: [f32; 3],
+        position: [f32; 3],
+        radius: f32,
+        #[serde(default = "area_samples")]
+        samples: u32,
+        #[serde(default)]
+        falloff: FalloffDescription,
+    },
+    // Rectangle centered on `position` with edges `u` and `v`
+    Rectangle {
+        intensity: f32,
+        #[serde(default = "white")]
+        color: [f32; 3],
+        position: [f32; 3],
+        u: [f32; 3],
+        v: [f32; 3],
+        #[serde(default = "area_samples")]
+        samples: u32,
+        #[serde(default)]
+        falloff: FalloffDescription,
+    },
+    // Lights everything within `outer_angle` degrees of `direction`, fading out from `inner_angle`
+    // on
+    Spot {
+        intensity: f32,
+        #[serde(default = "white")]
+        color: [f32; 3],
+        position: [f32; 3],
+        direction: [f32; 3],
+        #[serde(default)]
+        inner_angle: f32,
+        outer_angle: f32,
+        #[serde(default)]
+        falloff: FalloffDescription,
+    },
+}
+
+impl SceneDescription {
+    fn build(self, base_dir: &Path) -> Result<Scene, SceneError> {
+        let camera = self.camera.build().map_err(|e| SceneError::Invalid(format!("camera: {}", e)))?;
+        let mut materials = MaterialTable::new(base_dir);
+        for (name, material) in &self.materials {
+            let material = material.build(&mut materials.textures, &mut materials.maps).map_err(|e| SceneError::Invalid(format!("material {:?}: {}", name, e)))?;
+            materials.names.insert(name.clone(), materials.materials.len());
+            materials.materials.push(material);
+        }
+        let spheres = self.spheres.iter()
+            .enumerate()
+            .map(|(i, sphere)| sphere.build(&mut materials).map_err(|e| SceneError::Invalid(format!("sphere {}: {}", i, e))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let meshes = self.meshes.iter()
+            .enumerate()
+            .map(|(i, mesh)| mesh.build(base_dir, &mut materials).map_err(|e| SceneError::Invalid(format!("mesh {}: {}", i, e))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let planes = self.planes.iter()
+            .enumerate()
+            .map(|(i, plane)| plane.build(&mut materials).map_err(|e| SceneError::Invalid(format!("plane {}: {}", i, e))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut shapes = self.boxes.iter()
+            .enumerate()
+            .map(|(i, cuboid)| cuboid.build(&mut materials).map_err(|e| SceneError::Invalid(format!("box {}: {}", i, e))))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (i, disk) in self.disks.iter().enumerate() {
+            shapes.push(disk.build(&mut materials).map_err(|e| SceneError::Invalid(format!("disk {}: {}", i, e)))?);
+        }
+        for (i, cylinder) in self.cylinders.iter().enumerate() {
+            shapes.push(cylinder.build(&mut materials).map_err(|e| SceneError::Invalid(format!("cylinder {}: {}", i, e)))?);
+        }
+        let lights = self.lights.iter()
+            .enumerate()
+            .map(|(i, light)| light.build().map_err(|e| SceneError::Invalid(format!("light {}: {}", i, e))))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let environment = self.environment.build(base_dir).map_err(|e| SceneError::Invalid(format!("environment: {}", e)))?;
+
+        if self.render.width == 0 || self.render.height == 0 {
+            return Err(SceneError::Invalid(format!(
+                "render: resolution must not be zero, got {}x{}", self.render.width, self.render.height,
+            )));
+        }
+        if self.render.samples == 0 {
+            return Err(SceneError::Invalid("render: samples must be at least 1".to_string()));
+        }
+        // Each frame looks through a different point of the lens. Left on its own that's noise
+        // frozen in place, averaged over frames it's the blur
+        let mut render = self.render;
+        if camera.aperture > 0.0 {
+            render.accumulate = true;
+        }
+
+        let mut names = vec![None; materials.materials.len()];
+        for (name, &index) in &materials.names {
+            names[index] = Some(name.clone());
+        }
+        let layout = MaterialLayout {
+            names,
+            textures: materials.textures.paths(),
+            maps: materials.maps.paths(),
+        };
+
+        let scene = Scene {
+            camera,
+            spheres,
+            meshes,
+            planes,
+            shapes,
+            lights,
+            materials: materials.materials,
+            textures: materials.textures.into_images(),
+            maps: materials.maps.into_images(),
+            environment,
+            render,
+            layout,
+        };
+        scene.check_materials()?;
+        Ok(scene)
+    }
+}
+
+// Materials built so far, and which of them have names
+struct MaterialTable {
+    materials: Vec<Material>,
+    names: HashMap<String, usize>,
+    textures: TextureTable,
+    maps: TextureTable,
+}
+
+// Textures loaded so far. Materials using the same file share one
+struct TextureTable {
+    base_dir: PathBuf,
+    images: Vec<RgbaImage>,
+    paths: HashMap<PathBuf, usize>,
+}
+
+impl MaterialTable {
+    fn new(base_dir: &Path) -> Self {
+        MaterialTable {
+            materials: Vec::new(),
+            names: HashMap::new(),
+            textures: TextureTable::new(base_dir),
+            maps: TextureTable::new(base_dir),
+        }
+    }
+
+    // Index of the material an object asked for. Inline materials get added to the table, one
+    // entry per object
+    fn resolve(&mut self, source: &MaterialSource) -> Result<usize, String> {
+        match source {
+            MaterialSource::Named(name) => self.names.get(name)
+                .copied()
+                .ok_or_else(|| format!("no material named {:?}", name)),
+            MaterialSource::Inline(material) => {
+                self.materials.push(material.build(&mut self.textures, &mut self.maps)?);
+                Ok(self.materials.len() - 1)
+            }
+        }
+    }
+}
+
+impl TextureTable {
+    fn new(base_dir: &Path) -> Self {
+        TextureTable {
+            base_dir: base_dir.to_path_buf(),
+            images: Vec::new(),
+            paths: HashMap::new(),
+        }
+    }
+
+    // Index of the texture at path, loading it on first use
+    fn load(&mut self, path: &Path) -> Result<usize, String> {
+        let path = self.base_dir.join(path);
+        if let Some(&index) = self.paths.get(&path) {
+            return Ok(index);
+        }
+
+        let image = texture::load_texture(&path)
+            .map_err(|e| format!("could not load texture {}: {}", path.display(), e))?;
+        self.images.push(image);
+        self.paths.insert(path, self.images.len() - 1);
+        Ok(self.images.len() - 1)
+    }
+
+    // Where each texture was loaded from, in the order they were loaded
+    fn paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::new(); self.images.len()];
+        for (path, &index) in &self.paths {
+            paths[index] = path.clone();
+        }
+        paths
+    }
+
+    // Every texture, stretched to a common size. Stretching blurs or squashes a texture, so each
+    // one that needs it gets a warning
+    fn into_images(self) -> Vec<RgbaImage> {
+        let (width, height) = texture::common_size(&self.images);
+        for (index, path) in self.paths().iter().enumerate() {
+            let (w, h) = self.images[index].dimensions();
+            if (w, h) != (width, height) {
+                eprintln!(
+                    "warning: stretching {} from {}x{} to {}x{} to match the scene's other textures",
+                    path.display(), w, h, width, height,
+                );
+            }
+        }
+        texture::to_common_size(self.images)
+    }
+}
+
+impl CameraDescription {
+    fn build(self) -> Result<Camera, String> {
+        if !(-90.0..=90.0).contains(&self.pitch) {
+            return Err(format!("pitch must be within -90 and 90 degrees, got {}", self.pitch));
+        }
+        if self.speed <= 0.0 {
+            return Err(format!("speed must be positive, got {}", self.speed));
+        }
+        if self.sensitivity <= 0.0 {
+            return Err(format!("sensitivity must be positive, got {}", self.sensitivity));
+        }
+        // Fisheye views can see all the way around, perspective ones can't even reach 180 degrees
+        if self.projection == Projection::Fisheye {
+            if !(self.fov > 0.0 && self.fov <= MAX_FISHEYE_FOV.0) {
+                return Err(format!("fisheye fov must be between 0 and {} degrees, got {}", MAX_FISHEYE_FOV.0, self.fov));
+            }
+        } else if !(self.fov > 0.0 && self.fov < 180.0) {
+            return Err(format!("fov must be between 0 and 180 degrees, got {}", self.fov));
+        }
+        if self.ortho_height <= 0.0 {
+            return Err(format!("ortho_height must be positive, got {}", self.ortho_height));
+        }
+        if self.aspect < 0.0 {
+            return Err(format!("aspect must not be negative, got {}", self.aspect));
+        }
+        if self.near <= 0.0 {
+            return Err(format!("near must be positive, got {}", self.near));
+        }
+        if self.aperture < 0.0 {
+            return Err(format!("aperture must not be negative, got {}", self.aperture));
+        }
+        if self.focus_distance <= 0.0 {
+            return Err(format!("focus_distance must be positive, got {}", self.focus_distance));
+        }
+        if self.eye_separation < 0.0 {
+            return Err(format!("eye_separation must not be negative, got {}", self.eye_separation));
+        }
+        if self.convergence <= 0.0 {
+            return Err(format!("convergence must be positive, got {}", self.convergence));
+        }
+
+        let mut camera = Camera::from_origin();
+        camera.position = self.position.into();
+        camera.yaw = Deg(self.yaw).into();
+        camera.pitch = Deg(self.pitch).into();
+        camera.speed = self.speed;
+        camera.sensitivity = self.sensitivity;
+        camera.projection = self.projection;
+        camera.fov = Deg(self.fov).into();
+        camera.ortho_height = self.ortho_height;
+        camera.aspect = self.aspect;
+        camera.near = self.near;
+        camera.aperture = self.aperture;
+        camera.focus_distance = self.focus_distance;
+        camera.eye_separation = self.eye_separation;
+        camera.convergence = self.convergence;
+        Ok(camera)
+    }
+}
+
+impl MaterialDescription {
+    fn build(&self, textures: &mut TextureTable, maps: &mut TextureTable) -> Result<Material, String> {
+        check_color(self.color)?;
+        if self.specular != -1.0 && self.specular <= 0.0 {
+            return Err(format!("specular must be positive or -1 for none, got {}", self.specular));
+        }
+        if !(0.0..=1.0).contains(&self.reflective) {
+            return Err(format!("reflective must be within 0 and 1, got {}", self.reflective));
+        }
+        if !(0.0..=1.0).contains(&self.transparency) {
+            return Err(format!("transparency must be within 0 and 1, got {}", self.transparency));
+        }
+        if self.ior < 1.0 {
+            return Err(format!("ior must be at least 1, got {}", self.ior));
+        }
+        if self.emission.iter().any(|e| *e < 0.0) {
+            return Err(format!("emission must not be negative, got {:?}", self.emission));
+        }
+
+        let [r, g, b] = self.color;
+        let mut material = Material::new(&[r, g, b, 0.0], self.specular, self.reflective)
+            .transparent(self.transparency, self.ior)
+            .emissive(self.emission);
+        if let Some(path) = &self.texture {
+            material = material.textured(textures.load(path)?);
+        }
+        if let Some(pattern) = &self.pattern {
+            let (pattern, color, scale) = match *pattern {
+                PatternDescription::Checker { color, scale } => (Pattern::Checker, color, scale),
+                PatternDescription::Stripes { color, scale } => (Pattern::Stripes, color, scale),
+                PatternDescription::Noise { color, scale } => (Pattern::Noise, color, scale),
+            };
+            check_color(color).map_err(|e| format!("pattern {}", e))?;
+            if scale <= 0.0 {
+                return Err(format!("pattern scale must be positive, got {}", scale));
+            }
+            material = material.patterned(pattern, color, scale);
+        }
+        if let Some(path) = &self.normal_map {
+            material = material.normal_mapped(maps.load(path)?);
+        }
+        if let Some(path) = &self.bump_map {
+            material = material.bump_mapped(maps.load(path)?, self.bump_strength);
+        }
+        Ok(material)
+    }
+}
+
+impl SphereDescription {
+    fn build(&self, materials: &mut MaterialTable) -> Result<Sphere, String> {
+        if self.radius <= 0.0 {
+            return Err(format!("radius must be positive, got {}", self.radius));
+        }
+
+        Ok(Sphere::builder(self.center.into(), self.radius)
+            .material(materials.resolve(&self.material)?)
+            .build())
+    }
+}
+
+impl MeshDescription {
+    fn build(&self, base_dir: &Path, materials: &mut MaterialTable) -> Result<Mesh, String> {
+        if self.scale <= 0.0 {
+            return Err(format!("scale must be positive, got {}", self.scale));
+        }
+
+        let path = base_dir.join(&self.path);
+        let mut mesh = obj::load_obj(&path, materials.resolve(&self.material)?)
+            .map_err(|e| format!("could not load {}: {}", path.display(), e))?;
+        mesh.transform(self.position.into(), self.scale);
+        Ok(mesh)
+    }
+}
+
+impl PlaneDescription {
+    fn build(&self, materials: &mut MaterialTable) -> Result<Plane, String> {
+        if self.normal == [0.0; 3] {
+            return Err("normal must not be zero".to_string());
+        }
+
+        Ok(Plane::new(self.point.into(), self.normal.into(), materials.resolve(&self.material)?))
+    }
+}
+
+impl BoxDescription {
+    fn build(&self, materials: &mut MaterialTable) -> Result<Shape, String> {
+        if self.min.iter().zip(&self.max).any(|(min, max)| min >= max) {
+            return Err(format!("min must be below max on every axis, got {:?} and {:?}", self.min, self.max));
+        }
+
+        let material = materials.resolve(&self.material)?;
+        Ok(Shape::Cuboid(Cuboid::new(self.min.into(), self.max.into(), material)))
+    }
+}
+
+impl DiskDescription {
+    fn build(&self, materials: &mut MaterialTable) -> Result<Shape, String> {
+        if self.normal == [0.0; 3] {
+            return Err("normal must not be zero".to_string());
+        }
+        if self.radius <= 0.0 {
+            return Err(format!("radius must be positive, got {}", self.radius));
+        }
+
+        let material = materials.resolve(&self.material)?;
+        Ok(Shape::Disk(Disk::new(self.center.into(), self.normal.into(), self.radius, material)))
+    }
+}
+
+impl CylinderDescription {
+    fn build(&self, materials: &mut MaterialTable) -> Result<Shape, String> {
+        if self.axis == [0.0; 3] {
+            return Err("axis must not be zero".to_string());
+        }
+        if self.radius <= 0.0 {
+            return Err(format!("radius must be positive, got {}", self.radius));
+        }
+
+        let material = materials.resolve(&self.material)?;
+        Ok(Shape::Cylinder(Cylinder::new(self.base.into(), self.axis.into(), self.radius, material)))
+    }
+}
+
+impl FalloffDescription {
+    fn build(&self, light: Light) -> Result<Light, String> {
+        let AttenuationDescription { constant, linear, quadratic } = self.attenuation;
+        if constant < 0.0 || linear < 0.0 || quadratic < 0.0 {
+            return Err(format!("attenuation terms must not be negative, got {:?}", self.attenuation));
+        }
+        if constant + linear + quadratic <= 0.0 {
+            return Err("attenuation needs at least one term above 0".to_string());
+        }
+        if self.range < 0.0 {
+            return Err(format!("range must not be negative, got {}", self.range));
+        }
+        Ok(light.with_attenuation(constant, linear, quadratic).with_range(self.range))
+    }
+}
+
+impl LightDescription {
+    fn build(&self) -> Result<Light, String> {
+        let (light_type, intensity, color, position) = match *self {
+            LightDescription::Ambient { intensity, color } => (LightType::Ambient, intensity, color, None),
+            LightDescription::Point { intensity, color, position, .. } =>
+                (LightType::Point, intensity, color, Some(Vector3::from(position))),
+            LightDescription::Directional { intensity, color, direction } => {
+                if direction == [0.0; 3] {
+                    return Err("direction must not be zero".to_string());
+                }
+                // Light::new wants the way towards the light instead
+                (LightType::Directional, intensity, color, Some(-Vector3::from(direction)))
+            }
+            LightDescription::Sphere { intensity, color, position, radius, .. } => {
+                if radius <= 0.0 {
+                    return Err(format!("radius must be positive, got {}", radius));
+                }
+                (LightType::Sphere, intensity, color, Some(Vector3::from(position)))
+            }
+            LightDescription::Rectangle { intensity, color, position, u, v, .. } => {
+                if Vector3::from(u).cross(Vector3::from(v)) == Vector3::new(0.0, 0.0, 0.0) {
+                    return Err("edges u and v must be non-zero and not parallel".to_string());
+                }
+                (LightType::Rectangle, intensity, color, Some(Vector3::from(position)))
+            }
+            LightDescription::Spot { intensity, color, position, direction, inner_angle, outer_angle, .. } => {
+                if direction == [0.0; 3] {
+                    return Err("direction must not be zero".to_string());
+                }
+                if !(0.0..=180.0).contains(&outer_angle) {
+                    return Err(format!("outer_angle must be within 0 and 180, got {}", outer_angle));
+                }
+                if !(0.0..=outer_angle).contains(&inner_angle) {
+                    return Err(format!("inner_angle must be within 0 and outer_angle, got {}", inner_angle));
+                }
+                (LightType::Spot, intensity, color, Some(Vector3::from(position)))
+            }
+        };
+        check_color(color)?;
+        if intensity < 0.0 {
+            return Err(format!("intensity must not be negative, got {}", intensity));
+        }
+        if let LightDescription::Sphere { samples: 0, .. } | LightDescription::Rectangle { samples: 0, .. } = *self {
+            return Err("samples must be at least 1".to_string());
+        }
+
+        let mut light = Light::new(light_type, intensity, position).with_color(color);
+        if let LightDescription::Point { ref falloff, .. }
+        | LightDescription::Sphere { ref falloff, .. }
+        | LightDescription::Rectangle { ref falloff, .. }
+        | LightDescription::Spot { ref falloff, .. } = *self {
+            light = falloff.build(light)?;
+        }
+        Ok(match *self {
+            LightDescription::Sphere { radius, samples, .. } => light.with_radius(radius).with_samples(samples),
+            LightDescription::Rectangle { u, v, samples, .. } => light.with_edges(u.into(), v.into()).with_samples(samples),
+            LightDescription::Spot { direction, inner_angle, outer_angle, .. } =>
+                light.with_direction(direction.into()).with_cone(Deg(inner_angle), Deg(outer_angle)),
+            _ => light,
+        })
+    }
+}
+
+impl EnvironmentDescription {
+    fn build(&self, base_dir: &Path) -> Result<Environment, String> {
+        let background = match &self.background {
+            BackgroundDescription::Solid { color } => {
+                check_color(*color)?;
+                Background::Solid(*color)
+            }
+            BackgroundDescription::Gradient { top, bottom } => {
+                check_color(*top)?;
+                check_color(*bottom)?;
+                Background::Gradient { top: *top, bottom: *bottom }
+            }
+            BackgroundDescription::Image { path, intensity } => {
+                if *intensity < 0.0 {
+                    return Err(format!("intensity must not be negative, got {}", intensity));
+                }
+                let path = base_dir.join(path);
+                let image = environment::load_hdr(&path)
+                    .map_err(|e| format!("could not load {}: {}", path.display(), e))?;
+                Background::Image { image, intensity: *intensity }
+            }
+        };
+        Ok(Environment::new(background, self.lighting))
+    }
+}
+
+fn check_color(color: [f32; 3]) -> Result<(), String> {
+    if color.iter().any(|c| !(0.0..=1.0).contains(c)) {
+        return Err(format!("color components must be within 0 and 1, got {:?}", color));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Scene {
+        source.parse().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Fails to load with a SceneError::Invalid mentioning `expected`
+    fn assert_invalid(source: &str, expected: &str) {
+        match source.parse::<Scene>() {
+            Err(SceneError::Invalid(message)) => assert!(message.contains(expected), "{:?} doesn't mention {:?}", message, expected),
+            Err(e) => panic!("expected an invalid scene, got {}", e),
+            Ok(_) => panic!("expected an invalid scene, it loaded"),
+        }
+    }
+
+    #[test]
+    fn unknown_material_names() {
+        assert_invalid(
+            r#"Scene(spheres: [(center: (0.0, 0.0, 3.0), radius: 1, material: "missing")])"#,
+            "sphere 0: no material named \"missing\"",
+        );
+        assert_invalid(
+            r#"Scene(
+                materials: {"red": (color: (1.0, 0.0, 0.0))},
+                planes: [(point: (0.0, -1.0, 0.0), normal: (0.0, 1.0, 0.0), material: "Red")],
+            )"#,
+            "plane 0: no material named \"Red\"",
+        );
+    }
+
+    #[test]
+    fn zero_rectangle_edges() {
+        assert_invalid(
+            "Scene(lights: [Rectangle(intensity: 1.0, position: (0.0, 2.0, 4.0), u: (0.0, 0.0, 0.0), v: (0.0, 0.0, 1.0))])",
+            "edges u and v must be non-zero and not parallel",
+        );
+        assert_invalid(
+            "Scene(lights: [Rectangle(intensity: 1.0, position: (0.0, 2.0, 4.0), u: (1.0, 0.0, 0.0), v: (2.0, 0.0, 0.0))])",
+            "edges u and v must be non-zero and not parallel",
+        );
+    }
+
+    #[test]
+    fn bad_camera_settings() {
+        assert_invalid("Scene(camera: (fov: 0))", "fov must be");
+        assert_invalid("Scene(camera: (fov: 180))", "fov must be");
+        assert_invalid("Scene(camera: (projection: Fisheye, fov: 0))", "fisheye fov must be");
+        assert_invalid("Scene(camera: (projection: Fisheye, fov: 361))", "fisheye fov must be");
+        assert_eq!(Deg::from(parse("Scene(camera: (projection: Fisheye, fov: 360))").camera.fov).0.round(), 360.0);
+        assert_invalid("Scene(camera: (fov: -30))", "fov must be");
+        assert_invalid("Scene(camera: (aperture: -0.1))", "aperture must not be negative");
+        assert_invalid("Scene(camera: (focus_distance: 0))", "focus_distance must be positive");
+    }
+
+    #[test]
+    fn unknown_fields() {
+        for source in &[
+            "Scene(sphere: [])",
+            "Scene(camera: (feild_of_view: 90))",
+            "Scene(render: (sample: 4))",
+            r#"Scene(spheres: [(center: (0.0, 0.0, 3.0), radius: 1, material: (colour: (1.0, 0.0, 0.0)))])"#,
+        ] {
+            match source.parse::<Scene>() {
+                Err(SceneError::Parse(_)) => {}
+                Err(e) => panic!("{}: expected a parse error, got {}", source, e),
+                Ok(_) => panic!("{}: expected a parse error, it loaded", source),
+            }
+        }
+    }
+
+    #[test]
+    fn aperture_forces_accumulation() {
+        assert!(!parse("Scene()").render.accumulate);
+        assert!(parse("Scene(camera: (aperture: 0.1), render: (accumulate: false))").render.accumulate);
+        assert!(!parse("Scene(camera: (aperture: 0.0), render: (accumulate: false))").render.accumulate);
+    }
+
+    #[test]
+    fn reload_needs_the_same_materials() {
+        let scene = parse(r#"Scene(
+            materials: {"blue": (color: (0.0, 0.0, 1.0)), "red": (color: (1.0, 0.0, 0.0))},
+            spheres: [
+                (center: (0.0, 0.0, 3.0), radius: 1, material: "red"),
+                (center: (2.0, 0.0, 3.0), radius: 1, material: (color: (0.0, 1.0, 0.0))),
+            ],
+        )"#);
+
+        // Only the materials themselves changed
+        let tweaked = parse(r#"Scene(
+            materials: {"blue": (color: (0.0, 0.0, 0.5)), "red": (color: (0.5, 0.0, 0.0), specular: 10)},
+            spheres: [
+                (center: (0.0, 0.0, 3.0), radius: 1, material: "red"),
+                (center: (2.0, 0.0, 3.0), radius: 1, material: (color: (0.0, 0.5, 0.0))),
+            ],
+        )"#);
+        assert!(scene.check_reload(&tweaked).is_ok());
+
+        // "green" sorts between the others and would move "red" along
+        let added = parse(r#"Scene(
+            materials: {
+                "blue": (color: (0.0, 0.0, 1.0)), "green": (color: (0.0, 1.0, 0.0)), "red": (color: (1.0, 0.0, 0.0)),
+            },
+            spheres: [
+                (center: (0.0, 0.0, 3.0), radius: 1, material: "red"),
+                (center: (2.0, 0.0, 3.0), radius: 1, material: (color: (0.0, 1.0, 0.0))),
+            ],
+        )"#);
+        assert!(matches!(scene.check_reload(&added), Err(SceneError::Invalid(_))));
+
+        let renamed = parse(r#"Scene(
+            materials: {"blue": (color: (0.0, 0.0, 1.0)), "crimson": (color: (1.0, 0.0, 0.0))},
+            spheres: [
+                (center: (0.0, 0.0, 3.0), radius: 1, material: "crimson"),
+                (center: (2.0, 0.0, 3.0), radius: 1, material: (color: (0.0, 1.0, 0.0))),
+            ],
+        )"#);
+        assert!(matches!(scene.check_reload(&renamed), Err(SceneError::Invalid(_))));
+
+        let textured = parse(r#"Scene(
+            materials: {"blue": (color: (0.0, 0.0, 1.0)), "red": (color: (1.0, 0.0, 0.0))},
+            spheres: [
+                (center: (0.0, 0.0, 3.0), radius: 1, material: "red"),
+                (center: (2.0, 0.0, 3.0), radius: 1, material: (color: (1.0, 1.0, 1.0), texture: "tests/golden/bricks.png")),
+            ],
+        )"#);
+        assert!(matches!(scene.check_reload(&textured), Err(SceneError::Invalid(_))));
+    }
+}
@@ -0,0 +1,206 @@
+use cgmath::{InnerSpace, Vector3};
+
+use crate::bvh::Aabb;
+use crate::cs;
+use crate::object_traits::Uniform;
+
+// Kinds of shape, matching the SHAPE_ constants in the shader
+const SHAPE_BOX: i32 = 0;
+const SHAPE_DISK: i32 = 1;
+const SHAPE_CYLINDER: i32 = 2;
+
+/// Infinite plane, seen from both sides
+#[derive(Debug, Copy, Clone)]
+pub struct Plane {
+    pub(crate) point: Vector3<f32>,
+    pub(crate) normal: Vector3<f32>,
+    /// Index into the scene's materials
+    pub(crate) material: usize,
+}
+
+/// Axis-aligned box
+#[derive(Debug, Copy, Clone)]
+pub struct Cuboid {
+    pub(crate) min: Vector3<f32>,
+    pub(crate) max: Vector3<f32>,
+    /// Index into the scene's materials
+    pub(crate) material: usize,
+}
+
+/// Flat circle, seen from both sides
+#[derive(Debug, Copy, Clone)]
+pub struct Disk {
+    pub(crate) center: Vector3<f32>,
+    pub(crate) normal: Vector3<f32>,
+    pub(crate) radius: f32,
+    /// Index into the scene's materials
+    pub(crate) material: usize,
+}
+
+/// Cylinder closed off at both ends
+#[derive(Debug, Copy, Clone)]
+pub struct Cylinder {
+    /// Center of the bottom cap
+    pub(crate) base: Vector3<f32>,
+    /// From the center of the bottom cap to the center of the top one
+    pub(crate) axis: Vector3<f32>,
+    pub(crate) radius: f32,
+    /// Index into the scene's materials
+    pub(crate) material: usize,
+}
+
+/// Any of the shapes small enough to have bounds, which is all of them but planes. They share a
+/// buffer on the GPU
+#[derive(Debug, Copy, Clone)]
+pub enum Shape {
+    Cuboid(Cuboid),
+    Disk(Disk),
+    Cylinder(Cylinder),
+}
+
+impl Plane {
+    pub fn new(point: Vector3<f32>, normal: Vector3<f32>, material: usize) -> Self {
+        Plane {
+            point,
+            normal: normal.normalize(),
+            material,
+        }
+    }
+}
+
+impl Cuboid {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>, material: usize) -> Self {
+        Cuboid {
+            min,
+            max,
+            material,
+        }
+    }
+}
+
+impl Disk {
+    pub fn new(center: Vector3<f32>, normal: Vector3<f32>, radius: f32, material: usize) -> Self {
+        Disk {
+            center,
+            normal: normal.normalize(),
+            radius,
+            material,
+        }
+    }
+}
+
+impl Cylinder {
+    pub fn new(base: Vector3<f32>, axis: Vector3<f32>, radius: f32, material: usize) -> Self {
+        Cylinder {
+            base,
+            axis,
+            radius,
+            material,
+        }
+    }
+}
+
+impl Shape {
+    pub fn material(&self) -> usize {
+        match self {
+            Shape::Cuboid(cuboid) => cuboid.material,
+            Shape::Disk(disk) => disk.material,
+            Shape::Cylinder(cylinder) => cylinder.material,
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            Shape::Cuboid(cuboid) => Aabb { min: cuboid.min, max: cuboid.max },
+            Shape::Disk(disk) => {
+                let extent = circle_extent(disk.normal, disk.radius);
+                Aabb { min: disk.center - extent, max: disk.center + extent }
+            }
+            Shape::Cylinder(cylinder) => {
+                let extent = circle_extent(cylinder.axis.normalize(), cylinder.radius);
+                let top = cylinder.base + cylinder.axis;
+                Aabb::empty()
+                    .grow(cylinder.base - extent)
+                    .grow(cylinder.base + extent)
+                    .grow(top - extent)
+                    .grow(top + extent)
+            }
+        }
+    }
+}
+
+// How far a circle around the unit `normal` reaches along each axis. Its rim gets closer to an
+// axis the more the circle faces down it
+fn circle_extent(normal: Vector3<f32>, radius: f32) -> Vector3<f32> {
+    let reach = |n: f32| radius * (1.0 - n * n).max(0.0).sqrt();
+    Vector3::new(reach(normal.x), reach(normal.y), reach(normal.z))
+}
+
+impl Uniform for Plane {
+    type Uniform = cs::ty::Plane;
+
+    fn to_uniform(&self) -> Self::Uniform {
+        cs::ty::Plane {
+            point: self.point.into(),
+            normal: self.normal.extend(0.0).into(),
+            material: self.material as i32,
+        }
+    }
+}
+
+impl Uniform for Shape {
+    type Uniform = cs::ty::Shape;
+
+    fn to_uniform(&self) -> Self::Uniform {
+        match self {
+            Shape::Cuboid(cuboid) => cuboid.to_uniform(),
+            Shape::Disk(disk) => disk.to_uniform(),
+            Shape::Cylinder(cylinder) => cylinder.to_uniform(),
+        }
+    }
+}
+
+impl Uniform for Cuboid {
+    type Uniform = cs::ty::Shape;
+
+    fn to_uniform(&self) -> Self::Uniform {
+        cs::ty::Shape {
+            position: self.min.extend(1.0).into(),
+            direction: self.max.extend(1.0).into(),
+            kind: SHAPE_BOX,
+            radius: 0.0,
+            material: self.material as i32,
+            padding: 0.0,
+        }
+    }
+}
+
+impl Uniform for Disk {
+    type Uniform = cs::ty::Shape;
+
+    fn to_uniform(&self) -> Self::Uniform {
+        cs::ty::Shape {
+            position: self.center.extend(1.0).into(),
+            direction: self.normal.extend(0.0).into(),
+            kind: SHAPE_DISK,
+            radius: self.radius,
+            material: self.material as i32,
+            padding: 0.0,
+        }
+    }
+}
+
+impl Uniform for Cylinder {
+    type Uniform = cs::ty::Shape;
+
+    fn to_uniform(&self) -> Self::Uniform {
+        cs::ty::Shape {
+            position: self.base.extend(1.0).into(),
+            direction: self.axis.extend(0.0).into(),
+            kind: SHAPE_CYLINDER,
+            radius: self.radius,
+            material: self.material as i32,
+            padding: 0.0,
+        }
+    }
+}
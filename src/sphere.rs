@@ -1,4 +1,4 @@
-use cgmath::Vector4;
+use cgmath::{Vector3, Vector4};
 
 use crate::cs;
 use crate::object_traits::Uniform;
@@ -7,23 +7,46 @@ use crate::object_traits::Uniform;
 #[derive(Debug, Copy, Clone)]
 pub struct Sphere {
     pub(crate) center: Vector4<f32>,
-    pub(crate) radius: i32,
-    pub(crate) color: [f32; 4],
-    pub(crate) specular: f32,
-    pub(crate) reflective: f32,
+    pub(crate) radius: f32,
+    /// Index into the scene's materials
+    pub(crate) material: usize,
+}
+
+/// Sets up a `Sphere` one field at a time. Without a material it uses the scene's first one
+#[derive(Debug, Copy, Clone)]
+pub struct SphereBuilder {
+    sphere: Sphere,
 }
 
 impl Sphere {
-    pub fn new(x: f32, y: f32, z: f32, radius: i32, color: &[f32; 4], specular: i32, reflective: f32) -> Self {
-        let center = Vector4 { x, y, z, w: 1.0 };
-        Sphere {
-            center,
-            radius,
-            color: *color,
-            specular: specular as f32,
-            reflective
+    pub fn builder(center: Vector3<f32>, radius: f32) -> SphereBuilder {
+        SphereBuilder {
+            sphere: Sphere {
+                center: center.extend(1.0),
+                radius,
+                material: 0,
+            },
         }
     }
+
+    /// Whole number radius, for callers that predate `builder`
+    #[allow(dead_code)]
+    pub fn new(x: f32, y: f32, z: f32, radius: i32, material: usize) -> Self {
+        Sphere::builder(Vector3::new(x, y, z), radius as f32)
+            .material(material)
+            .build()
+    }
+}
+
+impl SphereBuilder {
+    pub fn material(mut self, material: usize) -> Self {
+        self.sphere.material = material;
+        self
+    }
+
+    pub fn build(self) -> Sphere {
+        self.sphere
+    }
 }
 
 impl Uniform for Sphere {
@@ -32,11 +55,23 @@ impl Uniform for Sphere {
     fn to_uniform(&self) -> Self::Uniform {
         cs::ty::Sphere {
             center: self.center.into(),
-            color: self.color.into(),
-            radius: (self.radius as f32).into(),
-            specular: self.specular.into(),
-            reflective: self.reflective.into(),
-            padding: 0.0,
+            radius: self.radius,
+            material: self.material as i32,
+            padding: [0.0; 2],
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_matches_builder() {
+        let sphere = Sphere::new(1.0, -2.0, 3.5, 4, 2);
+        let built = Sphere::builder(Vector3::new(1.0, -2.0, 3.5), 4.0).material(2).build();
+        assert_eq!(sphere.center, built.center);
+        assert_eq!(sphere.radius, 4.0);
+        assert_eq!(sphere.material, 2);
+    }
+}
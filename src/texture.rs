@@ -0,0 +1,33 @@
+use std::path::Path;
+
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+
+/// Loads a PNG or JPEG file, or anything else the image crate can decode
+pub fn load_texture(path: &Path) -> Result<RgbaImage, image::ImageError> {
+    Ok(image::open(path)?.into_rgba8())
+}
+
+/// The widest width and tallest height among the textures
+pub fn common_size(textures: &[RgbaImage]) -> (u32, u32) {
+    let width = textures.iter().map(|texture| texture.width()).max().unwrap_or(0);
+    let height = textures.iter().map(|texture| texture.height()).max().unwrap_or(0);
+    (width, height)
+}
+
+/// Stretches every texture to `common_size`. The shader keeps all of a scene's textures as layers
+/// of one array image, and those have to match in size. Textures that don't match get resized
+/// with a triangle filter, which blurs smaller ones and squashes ones with a different aspect
+/// ratio, so scenes look best with textures that already share a size
+pub fn to_common_size(textures: Vec<RgbaImage>) -> Vec<RgbaImage> {
+    let (width, height) = common_size(&textures);
+    textures.into_iter()
+        .map(|texture| {
+            if texture.dimensions() == (width, height) {
+                texture
+            } else {
+                imageops::resize(&texture, width, height, FilterType::Triangle)
+            }
+        })
+        .collect()
+}